@@ -0,0 +1,57 @@
+//! Files per second archived from a tree of many tiny files, where the cost of each entry
+//! outweighs its content.
+//!
+//! Run with `cargo bench --bench small_files`, optionally passing the number of files.
+//!
+//! Measured on one core with 20000 files, best of 5 in each of three runs: 38753 to 39227
+//! files/sec before file options and the read buffer were reused between entries, and
+//! 35863 to 38925 after. Within noise, so the reuse saves no measurable time.
+
+use clannad::{Deflate, Filter, SymlinkFilter, ZipDeflate};
+use std::{
+    fs::{create_dir_all, remove_dir_all, remove_file, write},
+    path::Path,
+    time::Instant,
+};
+
+const DEFAULT_FILES: usize = 5000;
+const ROUNDS: usize = 5;
+
+fn main() {
+    let files = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_FILES);
+    let root = Path::new("bench_small_files");
+    for i in 0..files {
+        let dir = root.join(format!("dir{}", i / 100));
+        create_dir_all(&dir).unwrap();
+        write(
+            dir.join(format!("file{}.txt", i)),
+            format!("content of file {}\n", i),
+        )
+        .unwrap();
+    }
+    let mut filter = SymlinkFilter::new(root);
+    filter.scan();
+    let entries = filter.files().clone().expect("tree is valid");
+
+    let mut best = f64::MAX;
+    for _ in 0..ROUNDS {
+        let started = Instant::now();
+        let mut deflate = ZipDeflate::new(Path::new("bench_small_files.zip"));
+        deflate.write_archive(&entries);
+        deflate.finish().unwrap();
+        best = best.min(started.elapsed().as_secs_f64());
+    }
+    println!(
+        "{} entries in {:.3}s, {:.0} files/sec (best of {})",
+        entries.len(),
+        best,
+        entries.len() as f64 / best,
+        ROUNDS
+    );
+
+    remove_file("bench_small_files.zip").unwrap();
+    remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,126 @@
+use crate::deflate::{Deflate, ZipDeflate, ZipDeflateBuilder};
+use crate::error::ClannadError;
+use crate::filter::FileInfo;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zip::ZipWriter;
+
+/// A growing archive for continuous backups, with an index of the paths it holds.
+///
+/// Each session appends the entries it is given to the archive and, once the archive is
+/// finished, their paths to the index, NUL terminated so any UTF-8 path survives. A new
+/// session reads the index back and skips the paths stored already.
+///
+/// The index only grows after the archive is finished, so it never lists entries the
+/// archive lacks. A session which stops before `finish` leaves the index as it was, but
+/// the archive without a central directory, to be salvaged with recovery tools.
+///
+/// Entries keep their first stored version: zip names are unique, so a changed file is
+/// not stored again under the same path.
+///
+/// ```
+/// # use clannad::filter::{Filter, SymlinkFilter};
+/// # use clannad::ArchiveLog;
+/// # use std::path::Path;
+/// let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+/// filter.scan();
+/// let changes: Vec<_> = filter.into_iter().collect();
+/// let (archive, index) = (Path::new("doc_log.zip"), Path::new("doc_log.index"));
+/// let mut log = ArchiveLog::open(archive, index).unwrap();
+/// assert_eq!(log.add_changes(&changes), 8);
+/// log.finish().unwrap();
+/// # std::fs::remove_file("doc_log.zip").unwrap();
+/// # std::fs::remove_file("doc_log.index").unwrap();
+/// ```
+pub struct ArchiveLog {
+    deflate: ZipDeflate,
+    index_path: PathBuf,
+    // paths stored by earlier sessions and this one
+    index: HashSet<String>,
+    // paths stored by this session, written to the index on finish
+    pending: Vec<String>,
+}
+
+impl ArchiveLog {
+    /// Continue the archive at `archive` with its index at `index`, creating both when
+    /// the archive does not exist yet.
+    pub fn open(archive: &Path, index: &Path) -> Result<Self, ClannadError> {
+        let deflate = match archive.try_exists()? {
+            true => {
+                let file = OpenOptions::new().read(true).write(true).open(archive)?;
+                ZipDeflate::from_zip_writer(ZipWriter::new_append(file)?)
+            }
+            false => ZipDeflateBuilder::new().build(archive)?,
+        };
+        let index_content = match index.try_exists()? {
+            true => fs::read_to_string(index)?,
+            false => String::new(),
+        };
+        Ok(Self {
+            deflate,
+            index_path: index.to_path_buf(),
+            index: index_content
+                .split_terminator('\0')
+                .map(|path| path.to_owned())
+                .collect(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Append the entries of `changes` not stored yet, returning how many were stored.
+    ///
+    /// Entries which fail are reported as by `ZipDeflate` and left for a later session.
+    pub fn add_changes(&mut self, changes: &[FileInfo]) -> usize {
+        let new: Vec<FileInfo> = changes
+            .iter()
+            .filter(|f| !self.index.contains(&f.path))
+            .cloned()
+            .collect();
+        let failed_before = self.deflate.skipped().len();
+        self.deflate.write_archive(&new);
+        let failed: HashSet<&str> = self.deflate.skipped()[failed_before..]
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect();
+        let stored: Vec<String> = new
+            .into_iter()
+            .filter(|f| !failed.contains(f.path.as_str()))
+            .map(|f| f.path)
+            .collect();
+        self.index.extend(stored.iter().cloned());
+        self.pending.extend_from_slice(&stored);
+        stored.len()
+    }
+
+    /// Whether `path` is stored, by an earlier session or this one.
+    pub fn contains(&self, path: &str) -> bool {
+        self.index.contains(path)
+    }
+
+    /// Number of paths stored across all sessions.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finish the archive, then record the paths of this session in the index.
+    pub fn finish(self) -> Result<(), ClannadError> {
+        self.deflate.finish()?;
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.index_path)?;
+        let content: String = self
+            .pending
+            .iter()
+            .flat_map(|p| [p.as_str(), "\0"])
+            .collect();
+        index.write_all(content.as_bytes())?;
+        Ok(())
+    }
+}
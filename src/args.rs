@@ -1,6 +1,9 @@
-use crate::{Deflate, ZipDeflate};
+use crate::filter::{expand_path, validate_root, FileInfo, RootError};
+use crate::{ClannadError, Deflate, Filter, SymlinkFilter, SymlinkFollowFilter, ZipDeflate};
 pub use clap::Parser;
-use std::path::Path;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(version = "0.1.0")]
@@ -13,24 +16,124 @@ pub struct Args {
         help = "whether to follow symlink"
     )]
     pub follow_symlink: bool,
+    #[arg(
+        short = 'x',
+        long = "exclude",
+        help = "skip files matching the glob, may be repeated"
+    )]
+    pub exclude: Vec<String>,
+    #[arg(
+        long = "exclude-from",
+        help = "read exclude globs from a file, one per line"
+    )]
+    pub exclude_from: Option<String>,
+    #[arg(
+        long = "from-file0",
+        help = "read more paths from a NUL-separated file, `-` for stdin"
+    )]
+    pub from_file0: Option<String>,
+    #[arg(
+        short = 'C',
+        long = "base-dir",
+        help = "resolve relative paths in this directory and store names relative to it"
+    )]
+    pub base_dir: Option<String>,
+    #[arg(
+        long = "no-expand",
+        default_value_t = false,
+        help = "take paths literally instead of expanding `~` and `$VAR`"
+    )]
+    pub no_expand: bool,
     pub filelist: Vec<String>,
 }
 
-pub fn run(args: Args) {
+/// NUL-separated paths read from the file `source`, or stdin for `-`, failing with
+/// [`ClannadError::InvalidPath`] on a path which is not UTF-8.
+fn read_list0(source: &str) -> Result<Vec<String>, ClannadError> {
+    let mut content = Vec::new();
+    match source {
+        "-" => io::stdin().read_to_end(&mut content),
+        _ => fs::File::open(source).and_then(|mut f| f.read_to_end(&mut content)),
+    }?;
+    content
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            String::from_utf8(name.to_vec()).map_err(|_| {
+                ClannadError::InvalidPath(format!(
+                    "{} is not valid UTF-8",
+                    String::from_utf8_lossy(name)
+                ))
+            })
+        })
+        .collect()
+}
+
+/// `info` stored under its path relative to `base`, still read from where it was found.
+fn relative_to(base: &Path, mut info: FileInfo) -> FileInfo {
+    if let Ok(relative) = Path::new(&info.path).strip_prefix(base) {
+        info.path = relative.to_str().expect("invalid path").to_owned();
+    }
+    info
+}
+
+/// Entries below `root`, failing with [`ClannadError::InvalidPath`] if it cannot be scanned.
+fn scan<F: Filter>(
+    root: &str,
+    excludes: &[String],
+    base: Option<&Path>,
+) -> Result<Vec<FileInfo>, ClannadError> {
+    let path = base.map_or(PathBuf::from(root), |base| base.join(root));
+    let invalid = |e| ClannadError::InvalidPath(format!("{} is not a valid root: {}", root, e));
+    validate_root(&path).map_err(invalid)?;
+    let mut filter = F::new(&path);
+    filter.exclude(excludes);
+    filter.scan();
+    if filter.files().is_none() {
+        return Err(invalid(RootError::NotFound));
+    }
+    Ok(match base {
+        Some(base) => filter.into_iter().map(|f| relative_to(base, f)).collect(),
+        None => filter.into_iter().collect(),
+    })
+}
+
+/// Archive the roots given on the command line, failing before the archive is created
+/// when one of them cannot be scanned.
+pub fn run(args: Args) -> Result<(), ClannadError> {
+    let mut excludes = args.exclude.clone();
+    if let Some(exclude_from) = &args.exclude_from {
+        fs::read_to_string(exclude_from)?
+            .lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .for_each(|l| excludes.push(l.to_owned()));
+    }
+    let mut filelist = args.filelist.clone();
+    if let Some(from_file0) = &args.from_file0 {
+        filelist.append(&mut read_list0(from_file0)?);
+    }
+    let mut base_dir = args.base_dir.clone();
+    if !args.no_expand {
+        filelist.iter_mut().for_each(|f| *f = expand_path(f));
+        base_dir = base_dir.map(|base| expand_path(&base));
+    }
+    let base = base_dir.as_deref().map(Path::new);
     let mut lists = Vec::new();
-    match args.follow_symlink {
-        true => {
-            args.filelist.iter().for_each(|f| {
-                lists.append(crate::scan_symlink_follow(Path::new(f)).as_mut().unwrap())
-            });
-        }
-        false => {
-            args.filelist
-                .iter()
-                .for_each(|f| lists.append(crate::scan_symlink(Path::new(f)).as_mut().unwrap()));
-        }
-    };
+    for f in &filelist {
+        lists.append(&mut match args.follow_symlink {
+            true => scan::<SymlinkFollowFilter>(f, &excludes, base)?,
+            false => scan::<SymlinkFilter>(f, &excludes, base)?,
+        });
+    }
     let mut deflate = ZipDeflate::new(Path::new(&args.zipfile_name));
     deflate.write_archive(&lists);
-    deflate.finish().unwrap();
+    deflate
+        .warnings()
+        .iter()
+        .for_each(|warning| eprintln!("{}", warning));
+    deflate
+        .skipped()
+        .iter()
+        .for_each(|(_, e)| eprintln!("{}", e));
+    deflate.finish()
 }
@@ -0,0 +1,161 @@
+use crate::deflate::{dir_entry_name, Deflate};
+use crate::error::ClannadError;
+use crate::filter::FileInfo;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Name of the entry mapping paths to content written by [`CasDeflate::finish`].
+pub const CAS_MANIFEST: &str = "manifest.tsv";
+
+/// A content addressed archive for backups holding many copies of the same files.
+///
+/// Each distinct content is stored once as `ab/cd/abcd...`, named by its hash, and a
+/// [`CAS_MANIFEST`] entry maps every path to it, one `<path><TAB><hash>` line each.
+/// Directories are listed as `<path>/` with an empty hash and symlinks as
+/// `<path><TAB>-> <target>`.
+///
+/// The hash is the CRC-32 and size of the content, which is cheap but not collision free,
+/// so files sharing it are compared byte by byte with the source of the stored blob and
+/// get a `-1`, `-2`... suffix when they differ. Contents without a known source, written
+/// outside of `write_archive`, are never deduplicated.
+///
+/// ```
+/// # use clannad::cas::CasDeflate;
+/// # use clannad::{Deflate, Filter, SymlinkFilter};
+/// # use std::path::Path;
+/// let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+/// filter.scan();
+/// let mut deflate = CasDeflate::new(Path::new("doc_cas.zip"));
+/// deflate.write_archive(filter.files().as_ref().unwrap());
+/// deflate.finish().unwrap();
+/// # std::fs::remove_file("doc_cas.zip").unwrap();
+/// ```
+pub struct CasDeflate {
+    writer: ZipWriter<File>,
+    // source of each stored blob by hash, None if it cannot be read again
+    blobs: HashMap<String, Option<PathBuf>>,
+    // manifest lines in the order the entries were written
+    entries: Vec<(String, String)>,
+    // source of the entry being written by write_archive
+    current: Option<PathBuf>,
+}
+
+impl CasDeflate {
+    /// Hash `content` is stored under, reusing a blob whose source holds the same bytes.
+    /// The flag tells whether the blob is still to be written.
+    fn blob_hash(&self, content: &[u8]) -> (String, bool) {
+        let hash = format!(
+            "{:08x}{:016x}",
+            crc32fast::hash(content),
+            content.len() as u64
+        );
+        for n in 0.. {
+            let candidate = match n {
+                0 => hash.clone(),
+                n => format!("{}-{}", hash, n),
+            };
+            match self.blobs.get(&candidate) {
+                None => return (candidate, true),
+                Some(Some(source)) if fs::read(source).is_ok_and(|c| c == content) => {
+                    return (candidate, false)
+                }
+                Some(_) => (),
+            }
+        }
+        unreachable!()
+    }
+
+    fn record(&mut self, name: String, value: String) {
+        self.entries.push((name, value));
+    }
+}
+
+impl Deflate for CasDeflate {
+    fn new(path: &Path) -> Self {
+        CasDeflate {
+            writer: ZipWriter::new(File::create(path).expect("archive file is not valid")),
+            blobs: HashMap::new(),
+            entries: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn begin_entry(&mut self, info: &FileInfo) {
+        self.current = Some(PathBuf::from(&info.content_path));
+    }
+
+    fn end_entry(&mut self) {
+        self.current = None;
+    }
+
+    fn write_dir(&mut self, dir: &Path) {
+        self.record(dir_entry_name(dir), String::new());
+    }
+
+    fn write_file(&mut self, file: &Path, content: &[u8]) {
+        let (hash, new) = self.blob_hash(content);
+        if new {
+            let blob = format!("{}/{}/{}", &hash[..2], &hash[2..4], hash);
+            let written = self
+                .writer
+                .start_file(blob, SimpleFileOptions::default())
+                .and_then(|_| Ok(self.writer.write_all(content)?));
+            if written.is_err() {
+                self.report(format!("{} is illegal file", file.to_str().unwrap()));
+                return;
+            }
+            self.blobs.insert(hash.clone(), self.current.clone());
+        }
+        let name = dir_entry_name(file).trim_end_matches('/').to_owned();
+        self.record(name, hash);
+    }
+
+    fn write_file_streaming(&mut self, file: &Path, reader: &mut dyn Read) -> u64 {
+        let mut content = Vec::new();
+        match reader.read_to_end(&mut content) {
+            Ok(read) => {
+                self.write_file(file, &content);
+                read as u64
+            }
+            Err(_) => {
+                self.report(format!("{} is illegal file", file.to_str().unwrap()));
+                0
+            }
+        }
+    }
+
+    fn write_symlink(&mut self, link: &Path, target: &Path) {
+        let name = dir_entry_name(link).trim_end_matches('/').to_owned();
+        self.record(name, format!("-> {}", target.to_str().unwrap()));
+    }
+
+    fn copy_entry(&mut self, from_name: &str, to_name: &str) -> Result<(), ClannadError> {
+        let value = self
+            .entries
+            .iter()
+            .find(|(name, _)| name == from_name)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| {
+                ClannadError::InvalidPath(format!("{} is not in the archive yet", from_name))
+            })?;
+        self.record(to_name.to_owned(), value);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), ClannadError> {
+        let manifest: String = self
+            .entries
+            .iter()
+            .map(|(name, value)| format!("{}\t{}\n", name, value))
+            .collect();
+        self.writer
+            .start_file(CAS_MANIFEST, SimpleFileOptions::default())?;
+        self.writer.write_all(manifest.as_bytes())?;
+        self.writer.finish()?;
+        Ok(())
+    }
+}
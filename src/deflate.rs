@@ -1,114 +1,2307 @@
-use zip::write::SimpleFileOptions;
-use zip::ZipWriter;
+use zip::result::ZipError;
+use zip::result::ZipResult;
+use zip::write::{FileOptionExtension, FileOptions, FullFileOptions, SimpleFileOptions};
+use zip::{CompressionMethod, DateTime, ZipWriter};
 
+use crate::error::ClannadError;
 use crate::filter::FileInfo;
 use crate::filter::FileType;
-use std::error::Error;
-use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use crate::filter::{self, Filter, SymlinkFilter, SymlinkPolicy, SymlinkResolution};
+use crate::limit::{Limiter, Permit};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Files at least this large are memory mapped by `write_archive`.
+#[cfg(feature = "memmap2")]
+pub const MMAP_MIN_SIZE: u64 = 1 << 20;
 
 pub trait Deflate {
     fn new(path: &Path) -> Self;
     fn write_dir(&mut self, dir: &Path);
     fn write_file(&mut self, file: &Path, content: &[u8]);
+    /// Write a file entry from `reader`, returning the number of bytes actually stored.
+    fn write_file_streaming(&mut self, file: &Path, reader: &mut dyn Read) -> u64;
+    /// Write a file entry from a memory map of `content`, reading it through a buffer
+    /// instead when it cannot be mapped.
+    #[cfg(feature = "memmap2")]
+    fn write_file_mapped(&mut self, file: &Path, content: &mut File) -> u64 {
+        // SAFETY: the map only lives for this call. Like any mmap, a file truncated by
+        // another process meanwhile may fault, which is the accepted caveat of the feature.
+        match unsafe { memmap2::Mmap::map(&*content) } {
+            Ok(map) => self.write_file_streaming(file, &mut &map[..]),
+            Err(_) => self.write_file_streaming(file, content),
+        }
+    }
     fn write_symlink(&mut self, link: &Path, target: &Path);
-    fn copy_dir(&mut self, src: &Path, dest: &Path);
-    fn finish(self) -> Result<(), Box<dyn Error>>;
+    /// Duplicate the directory entry of `src` as `dest`, reporting a failure.
+    #[deprecated(note = "use copy_entry, which takes archive names")]
+    fn copy_dir(&mut self, src: &Path, dest: &Path) {
+        if self
+            .copy_entry(&dir_entry_name(src), &dir_entry_name(dest))
+            .is_err()
+        {
+            self.report(format!(
+                "cannot copy {} to {}",
+                src.to_str().unwrap(),
+                dest.to_str().unwrap()
+            ));
+        }
+    }
+    /// Duplicate the already written entry `from_name` as `to_name`, both archive names.
+    fn copy_entry(&mut self, from_name: &str, to_name: &str) -> Result<(), ClannadError>;
+    fn finish(self) -> Result<(), ClannadError>;
+
+    /// Name an entry is stored under, the filesystem path unless overridden.
+    fn entry_name(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+
+    /// Which part of the scanned root is kept in stored names.
+    fn base(&self) -> BaseMode {
+        BaseMode::IncludeRoot
+    }
+
+    /// How stored names climbing out of the archive with `..` are handled.
+    fn traversal(&self) -> TraversalPolicy {
+        TraversalPolicy::Strip
+    }
+
+    /// Whether directories are left out, for names reduced to the file name by
+    /// `entry_name`.
+    fn flatten(&self) -> bool {
+        false
+    }
+
+    /// What happens to an entry whose stored name was taken by an earlier one.
+    fn collision(&self) -> CollisionPolicy {
+        CollisionPolicy::Skip
+    }
+
+    /// Whether `write_archive` adds directory entries for the parents of stored names
+    /// which were not given.
+    fn implied_dirs(&self) -> bool {
+        false
+    }
+
+    /// Whether `write_archive` first stores an [`INDEX_ENTRY_NAME`] entry listing the
+    /// given entries, see [`index_json`].
+    fn index_entry(&self) -> bool {
+        false
+    }
+
+    /// Path of the archive being written, left out by `write_archive` when it is among
+    /// the entries, as when archiving `.` into `./backup.zip`.
+    fn destination(&self) -> Option<&Path> {
+        None
+    }
+
+    /// What happens to stored names Windows cannot extract, see [`check_windows_name`].
+    fn portability(&self) -> PortabilityPolicy {
+        PortabilityPolicy::Ignore
+    }
+
+    /// Handle a problem with an entry which is still archived, printing it to stderr
+    /// unless overridden.
+    fn warn(&mut self, message: String) {
+        eprintln!("{}", message);
+    }
+
+    /// Handle an entry that could not be archived, printing it to stderr unless
+    /// overridden.
+    fn report(&mut self, message: String) {
+        eprintln!("{}", message);
+    }
+
+    /// Whether `write_archive` should stop before the next entry.
+    fn aborted(&self) -> bool {
+        false
+    }
+
+    /// Called by `write_archive` before the writes of each entry.
+    fn begin_entry(&mut self, _info: &FileInfo) {}
+
+    /// Called by `write_archive` after the writes of each entry.
+    fn end_entry(&mut self) {}
+
+    /// Called by `write_archive` with the first bytes of each regular file, `false` skips it.
+    fn sniff(&mut self, _head: &[u8]) -> bool {
+        true
+    }
 
+    /// Content of the regular file `f` when it is not read from `content_path` on the
+    /// filesystem, as for entries listed by [`ArchiveFilter`](crate::filter::ArchiveFilter).
+    fn read_content(&mut self, _f: &FileInfo) -> Option<io::Result<Vec<u8>>> {
+        None
+    }
+
+    /// Write every entry of `filelist`, in exactly the given order, which is also the
+    /// order of the central directory. Nothing is sorted; only the directories added by
+    /// `implied_dirs` are inserted, right before the first entry below them, and the
+    /// `index_entry` before all others.
     fn write_archive(&mut self, filelist: &Vec<FileInfo>) {
-        filelist.iter().for_each(|f| {
-            println!("{}, {}", f.path, f.content_path);
-            match &f.symlink_path {
-                Some(points_to) => self.write_symlink(Path::new(&f.path), Path::new(&points_to)),
-                None => match f.file_type {
-                    FileType::DIRECTORY => {
-                        //FIXME: if dest dir follows src, src will be dangling.
-                        //
-                        if f.path != f.content_path {
-                            self.copy_dir(Path::new(&f.content_path), Path::new(&f.path));
-                        }
-                        self.write_dir(Path::new(&f.content_path));
+        for c in find_collisions(filelist) {
+            self.warn(format!(
+                "{} is stored from {}",
+                c.name,
+                c.sources.join(", ")
+            ));
+        }
+        let root = match self.base() {
+            BaseMode::IncludeRoot => None,
+            BaseMode::ExcludeRoot | BaseMode::Basename => filelist.first(),
+        };
+        let mut stored = HashSet::new();
+        if self.index_entry() {
+            self.write_file(Path::new(INDEX_ENTRY_NAME), index_json(filelist).as_bytes());
+            stored.insert(PathBuf::from(INDEX_ENTRY_NAME));
+        }
+        filelist
+            .iter()
+            .for_each(|f| write_entry(self, f, root, &mut stored));
+    }
+
+    /// Like `write_archive` without collecting the entries first, so collisions between
+    /// sources are not reported.
+    fn write_archive_iter<I: IntoIterator<Item = FileInfo>>(&mut self, iter: I)
+    where
+        Self: Sized,
+    {
+        let mut iter = iter.into_iter();
+        let first = iter.next();
+        let root = match self.base() {
+            BaseMode::IncludeRoot => None,
+            BaseMode::ExcludeRoot | BaseMode::Basename => first.clone(),
+        };
+        let mut stored = HashSet::new();
+        first
+            .into_iter()
+            .chain(iter)
+            .for_each(|f| write_entry(self, &f, root.as_ref(), &mut stored));
+    }
+}
+
+/// Name of the index stored first by `write_archive` when `Deflate::index_entry` is set.
+pub const INDEX_ENTRY_NAME: &str = ".clannad-index.json";
+
+/// JSON array describing `filelist`, one object per entry in order with its `path`,
+/// `size`, `type` and, for symlinks kept as such, `target`.
+///
+/// ```
+/// # use clannad::deflate::index_json;
+/// # use clannad::filter::{FileInfo, FileType};
+/// # use std::path::Path;
+/// let dir = FileInfo::new(Path::new("docs"), Path::new("docs"), FileType::DIRECTORY, None);
+/// assert_eq!(
+///     index_json(&[dir]),
+///     "[\n{\"path\":\"docs\",\"size\":0,\"type\":\"DIRECTORY\"}\n]\n"
+/// );
+/// ```
+pub fn index_json(filelist: &[FileInfo]) -> String {
+    let entries: Vec<String> = filelist
+        .iter()
+        .map(|f| {
+            let target = match &f.symlink_path {
+                Some(target) => format!(",\"target\":{}", json_string(target)),
+                None => String::new(),
+            };
+            format!(
+                "{{\"path\":{},\"size\":{},\"type\":{}{}}}",
+                json_string(&f.path),
+                f.size,
+                json_string(f.file_type.as_str()),
+                target
+            )
+        })
+        .collect();
+    match entries.is_empty() {
+        true => "[]\n".to_owned(),
+        false => format!("[\n{}\n]\n", entries.join(",\n")),
+    }
+}
+
+/// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Store one entry of `write_archive`, named relative to `root`.
+///
+/// Names already in `stored` are skipped, so roots which overlap keep their first entry.
+fn write_entry<D: Deflate + ?Sized>(
+    deflate: &mut D,
+    f: &FileInfo,
+    root: Option<&FileInfo>,
+    stored: &mut HashSet<PathBuf>,
+) {
+    if deflate.aborted() || is_destination(f, deflate.destination()) {
+        return;
+    }
+    deflate.begin_entry(f);
+    store_entry(deflate, f, root, stored);
+    deflate.end_entry();
+}
+
+/// `options` compressing with `method` at `level`, the zip defaults for `None`.
+fn apply_method<T: FileOptionExtension>(
+    options: FileOptions<'static, T>,
+    method: Option<CompressionMethod>,
+    level: Option<i64>,
+) -> FileOptions<'static, T> {
+    let options = match method {
+        Some(method) => options.compression_method(method),
+        None => options,
+    };
+    options.compression_level(level)
+}
+
+/// Method whose level applies to entries of `method`, deflate being the zip default.
+fn chosen_level_method(method: Option<CompressionMethod>) -> CompressionMethod {
+    method.unwrap_or(CompressionMethod::Deflated)
+}
+
+/// `s` with backslashes, tabs and line breaks escaped for a metadata comment.
+fn escape_metadata(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Undo `escape_metadata`, `None` for an escape it does not produce.
+fn unescape_metadata(s: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Read back the map [`ZipDeflate::set_metadata`] stored in the comment of the archive
+/// at `path`, empty when the comment holds no metadata.
+///
+/// ```
+/// # use clannad::deflate::read_metadata;
+/// # use clannad::{Deflate, ZipDeflate};
+/// # use std::{collections::HashMap, path::Path};
+/// let mut deflate = ZipDeflate::new(Path::new("doc_metadata.zip"));
+/// let map = HashMap::from([("git-sha".to_owned(), "3f1d353".to_owned())]);
+/// deflate.set_metadata(map.clone()).unwrap();
+/// deflate.finish().unwrap();
+/// assert_eq!(read_metadata(Path::new("doc_metadata.zip")).unwrap(), map);
+/// # std::fs::remove_file("doc_metadata.zip").unwrap();
+/// ```
+pub fn read_metadata(path: &Path) -> Result<HashMap<String, String>, ClannadError> {
+    let archive = zip::ZipArchive::new(File::open(path)?)?;
+    let comment = String::from_utf8_lossy(archive.comment());
+    let mut lines = comment.split('\n');
+    if lines.next() != Some(METADATA_HEADER) {
+        return Ok(HashMap::new());
+    }
+    let invalid =
+        || ClannadError::InvalidMetadata(format!("{} has invalid metadata", path.display()));
+    lines
+        .map(|line| {
+            let (key, value) = line.split_once('\t').ok_or_else(invalid)?;
+            match (unescape_metadata(key), unescape_metadata(value)) {
+                (Some(key), Some(value)) => Ok((key, value)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// Whether `f` reads the archive at `destination` itself, which is still being written.
+///
+/// Only entries with the same file name are canonicalized, to keep the check cheap.
+fn is_destination(f: &FileInfo, destination: Option<&Path>) -> bool {
+    let (Some(destination), None) = (destination, &f.symlink_path) else {
+        return false;
+    };
+    let content = Path::new(&f.content_path);
+    if content.file_name() != destination.file_name() {
+        return false;
+    }
+    match (fs::canonicalize(content), fs::canonicalize(destination)) {
+        (Ok(content), Ok(destination)) => content == destination,
+        _ => false,
+    }
+}
+
+/// The writes of `write_entry`, between `begin_entry` and `end_entry` so the deflate can
+/// tell which entry a report is about.
+fn store_entry<D: Deflate + ?Sized>(
+    deflate: &mut D,
+    f: &FileInfo,
+    root: Option<&FileInfo>,
+    stored: &mut HashSet<PathBuf>,
+) {
+    let name = deflate.entry_name(&relative_to_base(Path::new(&f.path), root, deflate.base()));
+    let name = slashed_name(&name);
+    // the scanned root given as `.` or `/` has no entry of its own
+    if name.split('/').all(|part| part.is_empty() || part == ".") {
+        return;
+    }
+    let name = match sanitize_entry_name(&name, deflate.traversal()) {
+        Ok(name) => PathBuf::from(name),
+        Err(e) => {
+            deflate.report(e.to_string());
+            return;
+        }
+    };
+    if let Err(e) = check_windows_name(name.to_str().expect("invalid path")) {
+        match deflate.portability() {
+            PortabilityPolicy::Ignore => {}
+            PortabilityPolicy::Warn => deflate.warn(e.to_string()),
+            PortabilityPolicy::Reject => {
+                deflate.report(e.to_string());
+                return;
+            }
+        }
+    }
+    if deflate.flatten() && f.file_type == FileType::DIRECTORY && f.symlink_path.is_none() {
+        return;
+    }
+    let name = match (stored.contains(&name), deflate.collision()) {
+        (false, _) => name,
+        (true, CollisionPolicy::Skip) => {
+            deflate.warn(format!(
+                "{} is stored already, skipped",
+                name.to_str().unwrap()
+            ));
+            return;
+        }
+        (true, CollisionPolicy::Number) => numbered(&name, stored),
+        (true, CollisionPolicy::Fail) => {
+            deflate.report(format!(
+                "{} collides with an entry stored already",
+                name.to_str().unwrap()
+            ));
+            return;
+        }
+    };
+    stored.insert(name.clone());
+    if deflate.implied_dirs() {
+        let mut parents: Vec<_> = name
+            .ancestors()
+            .skip(1)
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .collect();
+        parents.reverse();
+        for parent in parents {
+            if stored.insert(parent.to_path_buf()) {
+                deflate.write_dir(parent);
+            }
+        }
+    }
+    let content_path = match resolve_content(f) {
+        Ok(path) => path,
+        Err(message) => {
+            deflate.report(message);
+            return;
+        }
+    };
+    match &f.symlink_path {
+        Some(points_to) => deflate.write_symlink(&name, Path::new(&points_to)),
+        None => match f.file_type {
+            FileType::DIRECTORY => {
+                // a followed directory symlink mirrors its target's entry when that
+                // was stored already, and is a plain directory otherwise
+                let content_name =
+                    deflate.entry_name(&relative_to_base(&content_path, root, deflate.base()));
+                let content_name =
+                    sanitize_entry_name(&slashed_name(&content_name), TraversalPolicy::Strip)
+                        .unwrap_or_default();
+                if Path::new(&f.path) == content_path
+                    || deflate
+                        .copy_entry(
+                            &dir_entry_name(Path::new(&content_name)),
+                            &dir_entry_name(&name),
+                        )
+                        .is_err()
+                {
+                    deflate.write_dir(&name);
+                }
+            }
+            FileType::REGULAR => match deflate.read_content(f) {
+                Some(Ok(content)) => {
+                    let head = &content[..content.len().min(MAGIC_LEN as usize)];
+                    if deflate.sniff(head) {
+                        deflate.write_file_streaming(&name, &mut &content[..]);
                     }
-                    FileType::REGULAR => self.write_file(
-                        Path::new(&f.path),
-                        fs::read_to_string(&f.content_path)
-                            .expect(&format!("{} is not valid", f.content_path))
-                            .as_bytes(),
-                    ),
-                    _ => unreachable!(),
-                },
+                }
+                Some(Err(_)) => deflate.report(format!("{} is not valid", f.content_path)),
+                None => store_file(deflate, f, &name, &content_path),
+            },
+            _ => deflate.report(format!(
+                "{} is neither a file nor a directory",
+                f.content_path
+            )),
+        },
+    };
+}
+
+/// Store the regular file `f` as `name`, reading it from `content_path`.
+fn store_file<D: Deflate + ?Sized>(
+    deflate: &mut D,
+    f: &FileInfo,
+    name: &Path,
+    content_path: &Path,
+) {
+    match File::open(content_path) {
+        Ok(mut content) => {
+            let mut head = Vec::new();
+            let _ = (&mut content).take(MAGIC_LEN).read_to_end(&mut head);
+            if !deflate.sniff(&head) {
+                return;
+            }
+            if content.rewind().is_err() {
+                deflate.report(format!("{} is not valid", f.content_path));
+                return;
+            }
+            #[cfg(feature = "memmap2")]
+            let written = if f.size >= MMAP_MIN_SIZE {
+                deflate.write_file_mapped(name, &mut content)
+            } else {
+                deflate.write_file_streaming(name, &mut content)
             };
+            #[cfg(not(feature = "memmap2"))]
+            let written = deflate.write_file_streaming(name, &mut content);
+            if written != f.size {
+                deflate.warn(format!(
+                    "{} changed size while reading, {} of {} bytes stored",
+                    f.content_path, written, f.size
+                ));
+            }
+        }
+        Err(_) => deflate.report(format!("{} is not valid", f.content_path)),
+    }
+}
+
+/// Where the content of `f` is read from, following `content_path` when it is itself a
+/// symlink in place of a differing `path`.
+///
+/// The chain must end at an existing entry of the recorded type, so a link which dangles,
+/// loops or now points to a directory instead of a file is reported rather than stored.
+fn resolve_content(f: &FileInfo) -> Result<PathBuf, String> {
+    let content_path = Path::new(&f.content_path);
+    let is_link = fs::symlink_metadata(content_path).is_ok_and(|m| m.file_type().is_symlink());
+    if f.symlink_path.is_some() || f.path == f.content_path || !is_link {
+        return Ok(content_path.to_path_buf());
+    }
+    let resolution = filter::resolve_symlink(content_path);
+    let matches = match f.file_type {
+        FileType::DIRECTORY => resolution.target.is_dir(),
+        FileType::REGULAR => resolution.target.is_file(),
+        _ => true,
+    };
+    match resolution {
+        SymlinkResolution { looped: true, .. } => {
+            Err(format!("{} is a symlink loop", f.content_path))
+        }
+        SymlinkResolution { exists: false, .. } => {
+            Err(format!("{} is a broken symlink", f.content_path))
+        }
+        _ if !matches => Err(format!(
+            "{} no longer points to a {}",
+            f.content_path,
+            match f.file_type {
+                FileType::DIRECTORY => "directory",
+                _ => "file",
+            }
+        )),
+        SymlinkResolution { target, .. } => Ok(target),
+    }
+}
+
+/// Scan `root` and archive its entries at the same time, the scan running at most `bound`
+/// entries ahead of the writer.
+///
+/// The result matches scanning first and handing the list to `write_archive_iter`, while
+/// only the entries in flight are held in memory. A missing root leaves the archive empty.
+pub fn pipeline<D: Deflate>(
+    root: &Path,
+    policy: SymlinkPolicy,
+    excludes: &[String],
+    deflate: &mut D,
+    bound: usize,
+) {
+    let (sender, receiver) = mpsc::sync_channel(bound);
+    thread::scope(|scope| {
+        scope.spawn(|| filter::scan_to(root, policy, excludes, sender));
+        deflate.write_archive_iter(receiver);
+    });
+}
+
+/// Archive the entries of `src` modified after `existing` was into a new archive at
+/// `dest`, returning how many were selected.
+///
+/// Symlinks are retained and compared by their own modification time. A directory is
+/// only included when its listing changed, so unchanged parents of a newer file are left
+/// out. `dest` may replace `existing` once written.
+pub fn archive_incremental(
+    src: &Path,
+    existing: &Path,
+    dest: &Path,
+) -> Result<usize, ClannadError> {
+    let since = fs::metadata(existing)?.modified()?;
+    let mut filter = SymlinkFilter::new(src);
+    filter.scan();
+    if filter.files().is_none() {
+        return Err(ClannadError::InvalidPath(format!(
+            "{} cannot be scanned",
+            src.display()
+        )));
+    }
+    let modified = |f: &FileInfo| {
+        match f.symlink_path {
+            Some(_) => fs::symlink_metadata(&f.path),
+            None => fs::metadata(&f.content_path),
+        }
+        .and_then(|m| m.modified())
+        .is_ok_and(|time| time > since)
+    };
+    let files: Vec<FileInfo> = filter.into_iter().filter(modified).collect();
+    let mut deflate = ZipDeflate::new(dest);
+    deflate.write_archive(&files);
+    deflate.finish()?;
+    Ok(files.len())
+}
+
+/// Header id of the NTFS extra field.
+const NTFS_EXTRA_ID: u16 = 0x000a;
+
+/// Header id of the Info-ZIP unix extra field carrying the owner ids.
+const UNIX_OWNER_EXTRA_ID: u16 = 0x7875;
+
+/// The unix epoch in NTFS ticks.
+const NTFS_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// Comment of an archive whose writing was cancelled, marking it incomplete.
+pub const CANCELLED_COMMENT: &str = "incomplete: cancelled before every entry was written";
+
+/// First line of an archive comment written by [`ZipDeflate::set_metadata`].
+pub const METADATA_HEADER: &str = "clannad-metadata";
+
+/// Size of the buffer files are streamed through unless configured.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Leading bytes of each file checked for text, as many as git checks.
+const TEXT_SNIFF_LEN: usize = 8000;
+
+/// Bytes read from each file to recognize already compressed content.
+const MAGIC_LEN: u64 = 8;
+
+/// Leading bytes of common compressed formats: zip, gzip, bzip2, xz, zstd, 7z, jpeg, png.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"PK\x03\x04",
+    b"\x1f\x8b",
+    b"BZh",
+    b"\xfd7zXZ\x00",
+    b"\x28\xb5\x2f\xfd",
+    b"7z\xbc\xaf\x27\x1c",
+    b"\xff\xd8\xff",
+    b"\x89PNG\r\n\x1a\n",
+];
+
+fn is_compressed(head: &[u8]) -> bool {
+    COMPRESSED_MAGIC.iter().any(|magic| head.starts_with(magic))
+}
+
+/// Share of the original size a compressing method is assumed to keep by `plan`.
+const ESTIMATED_RATIO: f64 = 0.5;
+
+/// Summary of what archiving a file list would produce, see [`plan`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArchivePlan {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    // recorded sizes of the regular files
+    pub total_size: u64,
+    // rough size of the compressed file data, without headers
+    pub estimated_size: u64,
+}
+
+/// Count the entries of `files` and estimate their compressed size with `method`, without
+/// writing anything.
+///
+/// Sizes are the ones recorded by the scan. Already compressed files, recognized by their
+/// magic bytes, are counted at full size, other files at half size unless `method` is
+/// `Stored`.
+pub fn plan(files: &[FileInfo], method: CompressionMethod) -> ArchivePlan {
+    let mut plan = ArchivePlan::default();
+    files
+        .iter()
+        .for_each(|f| match (&f.symlink_path, f.file_type) {
+            (Some(_), _) => plan.symlinks += 1,
+            (None, FileType::DIRECTORY) => plan.dirs += 1,
+            (None, FileType::REGULAR) => {
+                plan.files += 1;
+                plan.total_size += f.size;
+                let mut head = Vec::new();
+                let _ = File::open(&f.content_path)
+                    .and_then(|file| file.take(MAGIC_LEN).read_to_end(&mut head));
+                plan.estimated_size +=
+                    match method == CompressionMethod::Stored || is_compressed(&head) {
+                        true => f.size,
+                        false => (f.size as f64 * ESTIMATED_RATIO).ceil() as u64,
+                    };
+            }
+            _ => {}
         });
+    plan
+}
+
+/// How `ZipDeflate` stores retained symlinks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// A symlink entry holding the target path, which extractors without symlink support
+    /// write out as a small text file.
+    Store,
+    /// What the symlink resolves to: the content of a file target, or an empty directory
+    /// for a directory target. Dangling and looping links are reported.
+    Resolve,
+}
+
+/// What `ZipDeflate` does with files whose content is already compressed, judged by
+/// their leading magic bytes rather than their extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressedPolicy {
+    /// Compress them like any other file, without reading ahead.
+    Deflate,
+    /// Store them uncompressed.
+    Store,
+    /// Leave them out of the archive with a warning.
+    Skip,
+}
+
+/// What `ZipDeflate` does when an entry cannot be archived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop at the first failure; `finish` removes the archive and returns the error.
+    Abort,
+    /// Record the failure in `ZipDeflate::skipped` and go on with the remaining entries.
+    SkipWithWarning,
+}
+
+/// Unix permissions `ZipDeflate` stores for files and directories.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermissionPolicy {
+    /// The `zip` crate defaults, apart from read-only files kept by `with_readonly`.
+    Default,
+    /// Store every file with mode `file` and every directory with mode `dir`, for example
+    /// 0o644 and 0o755, whatever the source permissions. Symlinks are untouched.
+    Force { file: u32, dir: u32 },
+}
+
+/// What happens to `..` components in stored names, which would let extractors write
+/// outside their destination (zip-slip).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraversalPolicy {
+    /// Skip the entry and report it.
+    Reject,
+    /// Drop the `..` components, `../evil` is stored as `evil`.
+    Strip,
+}
+
+/// Whether `ZipDeflate` marks file entries as text in their internal attributes, which
+/// some extractors use to convert line endings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextPolicy {
+    /// Mark files whose first 8000 bytes are UTF-8 without NUL bytes.
+    Detect,
+    /// Mark every file.
+    Always,
+    /// Mark no file, leaving every entry binary as the zip crate writes it.
+    Never,
+}
+
+/// Whether `head`, the start of a file, is UTF-8 without NUL bytes. A character cut off
+/// at the end of `head` is allowed.
+fn looks_like_text(head: &[u8]) -> bool {
+    !head.contains(&0)
+        && match std::str::from_utf8(head) {
+            Ok(_) => true,
+            Err(e) => e.error_len().is_none(),
+        }
+}
+
+/// What `write_archive` does with an entry whose stored name is already taken, such as
+/// files of the same name in different directories of a flattened archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Keep the first entry and skip the others with a warning.
+    Skip,
+    /// Store the others with a numeric suffix, `log.txt` as `log-1.txt`, `log-2.txt`...
+    /// Entries of overlapping roots are stored again this way.
+    Number,
+    /// Report the others as failures, which aborts the archive under
+    /// `FailurePolicy::Abort`.
+    Fail,
+}
+
+/// `name` with the first numeric suffix not in `stored`, before the extension.
+fn numbered(name: &Path, stored: &HashSet<PathBuf>) -> PathBuf {
+    let stem = name
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let extension = name.extension().and_then(|e| e.to_str());
+    (1..)
+        .map(|n| match extension {
+            Some(extension) => name.with_file_name(format!("{}-{}.{}", stem, n, extension)),
+            None => name.with_file_name(format!("{}-{}", stem, n)),
+        })
+        .find(|candidate| !stored.contains(candidate))
+        .expect("a free name")
+}
+
+/// `path` as a stored name, its components joined by `/` without the root or drive of
+/// an absolute path, so absolute sources are stored below their filesystem root.
+pub(crate) fn slashed_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Prefix(_) | Component::RootDir => None,
+            c => Some(c.as_os_str().to_string_lossy()),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Device names Windows reserves in every directory, whatever the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check a stored name against rules every extractor can honour safely, returning it with
+/// `\` turned into `/` and empty or `.` components dropped.
+///
+/// Absolute names, drive letters and control characters are rejected, and `..`
+/// components are dropped or rejected as `policy` says. `write_archive` applies it to
+/// every stored name, and leaves names which are only a problem on Windows, such as
+/// `CON`, to its [`PortabilityPolicy`].
+pub fn sanitize_entry_name(name: &str, policy: TraversalPolicy) -> Result<String, ClannadError> {
+    let invalid = |reason: &str| Err(ClannadError::InvalidPath(format!("{} {}", name, reason)));
+    let name_slashed = name.replace('\\', "/");
+    if name_slashed.starts_with('/') {
+        return invalid("is absolute");
+    }
+    if name.chars().any(char::is_control) {
+        return invalid("contains a control character");
+    }
+    let mut parts = Vec::new();
+    for part in name_slashed.split('/') {
+        match (part, policy) {
+            ("" | ".", _) | ("..", TraversalPolicy::Strip) => continue,
+            ("..", TraversalPolicy::Reject) => return invalid("escapes the archive root"),
+            _ => {}
+        }
+        let mut chars = part.chars();
+        if parts.is_empty()
+            && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.next() == Some(':')
+        {
+            return invalid("has a drive letter");
+        }
+        parts.push(part);
+    }
+    match parts.is_empty() {
+        true => invalid("is empty"),
+        false => Ok(parts.join("/")),
+    }
+}
+
+/// Characters Windows does not allow in file names, besides control characters.
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Check a stored name for what Windows cannot extract: control characters, the
+/// characters `< > : " | ? *`, components ending in a dot or space and names Windows
+/// reserves for devices, such as `CON` or `lpt1.txt`.
+///
+/// ```
+/// # use clannad::deflate::check_windows_name;
+/// assert!(check_windows_name("docs/notes.txt").is_ok());
+/// assert!(check_windows_name("docs/bad:name").is_err());
+/// assert!(check_windows_name("trailing./notes.txt").is_err());
+/// assert!(check_windows_name("docs/aux.txt").is_err());
+/// ```
+pub fn check_windows_name(name: &str) -> Result<(), ClannadError> {
+    let invalid = |reason: &str| {
+        Err(ClannadError::InvalidPath(format!(
+            "{} {} on Windows",
+            name, reason
+        )))
+    };
+    if name.chars().any(char::is_control) {
+        return invalid("contains a control character");
+    }
+    if name.contains(WINDOWS_FORBIDDEN) {
+        return invalid("contains a character not allowed");
+    }
+    let reserved = name.split('/').any(|part| {
+        let stem = part.split('.').next().unwrap_or(part).trim_end();
+        RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem))
+    });
+    if reserved {
+        return invalid("is reserved");
     }
+    let trailing = name
+        .split('/')
+        .any(|part| part.ends_with('.') || part.ends_with(' '));
+    match trailing {
+        true => invalid("has a component ending in a dot or space, dropped"),
+        false => Ok(()),
+    }
+}
+
+/// What `write_archive` does with stored names Windows cannot extract, see
+/// [`check_windows_name`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortabilityPolicy {
+    /// Store them without a word.
+    Ignore,
+    /// Store them and warn, see [`ZipDeflate::warnings`].
+    Warn,
+    /// Skip the entry and report it.
+    Reject,
+}
+
+/// Which part of the scanned root is kept in stored names.
+///
+/// The root is the first entry handed to `write_archive`, as produced by the filters.
+/// A directory root (including a followed symlink) is itself dropped by `ExcludeRoot`
+/// and kept by its file name alone by `Basename`, while a single file or retained
+/// symlink root is stored by its file name in both modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaseMode {
+    /// Store paths as scanned, `resources/normalfolder/level1`.
+    IncludeRoot,
+    /// Store paths relative to the root directory, `level1`.
+    ExcludeRoot,
+    /// Store paths under the last component of the root, `normalfolder/level1`. Roots
+    /// without one, such as `.`, are handled like `ExcludeRoot`.
+    Basename,
+}
+
+/// `path` relative to the directory holding the contents of `root`, below the file name
+/// of a directory `root` under `BaseMode::Basename`.
+fn relative_to_base(path: &Path, root: Option<&FileInfo>, base: BaseMode) -> PathBuf {
+    let root = match root {
+        Some(root) => root,
+        None => return path.to_path_buf(),
+    };
+    let is_dir = root.file_type == FileType::DIRECTORY && root.symlink_path.is_none();
+    let (path_base, content_base) = if is_dir {
+        (Path::new(&root.path), Path::new(&root.content_path))
+    } else {
+        let parent = Path::new(&root.path).parent().unwrap_or(Path::new(""));
+        (parent, parent)
+    };
+    let relative = match path
+        .strip_prefix(path_base)
+        .or_else(|_| path.strip_prefix(content_base))
+    {
+        Ok(relative) => relative,
+        Err(_) => return path.to_path_buf(),
+    };
+    match (base, Path::new(&root.path).file_name()) {
+        (BaseMode::Basename, Some(name)) if is_dir => Path::new(name).join(relative),
+        _ => relative.to_path_buf(),
+    }
+}
+
+/// Entries from different sources that would be stored under the same name.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Collision {
+    pub name: String,
+    // content paths competing for the name, in list order
+    pub sources: Vec<String>,
+}
+
+/// Find stored names claimed by more than one source file.
+///
+/// ```
+/// # use clannad::deflate::find_collisions;
+/// # use clannad::filter::{FileInfo, FileType};
+/// # use std::path::Path;
+/// let files = vec![
+///     FileInfo::new(Path::new("a"), Path::new("x/a"), FileType::REGULAR, None),
+///     FileInfo::new(Path::new("a"), Path::new("y/a"), FileType::REGULAR, None),
+/// ];
+/// assert_eq!(find_collisions(&files)[0].sources, vec!["x/a", "y/a"]);
+/// ```
+pub fn find_collisions(filelist: &[FileInfo]) -> Vec<Collision> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut sources: HashMap<&str, Vec<&str>> = HashMap::new();
+    filelist.iter().for_each(|f| {
+        let entry = sources.entry(f.path.as_str()).or_insert_with(|| {
+            names.push(f.path.as_str());
+            Vec::new()
+        });
+        if !entry.contains(&f.content_path.as_str()) {
+            entry.push(f.content_path.as_str());
+        }
+    });
+    names
+        .into_iter()
+        .filter(|name| sources[name].len() > 1)
+        .map(|name| Collision {
+            name: name.to_owned(),
+            sources: sources[name].iter().map(|s| s.to_string()).collect(),
+        })
+        .collect()
+}
+
+/// Directory entry name with `/` separators and exactly one trailing slash.
+pub(crate) fn dir_entry_name(dir: &Path) -> String {
+    let mut name = dir
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => s.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    name.push('/');
+    name
+}
+
+type MtimeFn = Box<dyn Fn(&FileInfo) -> DateTime>;
+
+/// Transform of a file's content before storing, see [`ZipDeflate::with_content_transform`].
+pub type ContentTransform = Box<dyn Fn(&FileInfo, &[u8]) -> Vec<u8>>;
+
+/// Compression levels `method` accepts, `None` for methods without levels such as
+/// `Stored`.
+pub fn level_range(method: CompressionMethod) -> Option<RangeInclusive<i64>> {
+    match method {
+        CompressionMethod::Deflated => Some(0..=9),
+        CompressionMethod::Bzip2 => Some(1..=9),
+        CompressionMethod::Zstd => Some(1..=22),
+        _ => None,
+    }
+}
+
+/// Add `level` for `method` to `levels`, replacing an earlier one.
+fn set_level(
+    levels: &mut Vec<(CompressionMethod, i64)>,
+    method: CompressionMethod,
+    level: i64,
+) -> Result<(), ClannadError> {
+    if !level_range(method).is_some_and(|range| range.contains(&level)) {
+        return Err(ClannadError::InvalidOption(format!(
+            "{:?} does not accept compression level {}",
+            method, level
+        )));
+    }
+    levels.retain(|(m, _)| *m != method);
+    levels.push((method, level));
+    Ok(())
 }
 
 /// Create Zip file using basic option.
 pub struct ZipDeflate {
     writer: ZipWriter<File>,
+    // removed again when the archive is aborted and never stored as an entry, unknown
+    // for build_writer
+    path: Option<PathBuf>,
+    // compression method without a more specific rule, the zip default if unset
+    method: Option<CompressionMethod>,
+    extension_methods: HashMap<String, CompressionMethod>,
+    // methods of single entries by path, before the extension rules
+    entry_methods: HashMap<String, CompressionMethod>,
+    // compression level chosen for each method
+    levels: Vec<(CompressionMethod, i64)>,
+    prefix: Option<String>,
+    // suffix rewrites of stored file names, applied in order
+    rename_rules: Vec<(String, String)>,
+    base: BaseMode,
+    preserve_readonly: bool,
+    permission_policy: PermissionPolicy,
+    traversal: TraversalPolicy,
+    link_policy: LinkPolicy,
+    flatten: bool,
+    collision: CollisionPolicy,
+    implied_dirs: bool,
+    index_entry: bool,
+    // entry being written by write_archive
+    current: Option<FileInfo>,
+    failure_policy: FailurePolicy,
+    // first failure under FailurePolicy::Abort
+    failure: Option<String>,
+    mtime_fn: Option<MtimeFn>,
+    compressed_policy: CompressedPolicy,
+    // whether the current entry was sniffed as already compressed
+    compressed: bool,
+    buffer_size: usize,
+    // read buffer of write_file_streaming, kept between entries
+    buffer: Vec<u8>,
+    // options with the method and level of each method used so far
+    method_options: Vec<(Option<CompressionMethod>, SimpleFileOptions)>,
+    extended_timestamps: bool,
+    owner: bool,
+    sidecar: Option<PathBuf>,
+    // CRC-32 of each file's content by stored name, gathered for the sidecar
+    checksums: Vec<(String, u32)>,
+    // host system and specification version, as written to the central directory
+    made_by: Option<(u8, u8)>,
+    text_policy: TextPolicy,
+    // stored names of the files marked as text
+    text_entries: HashSet<String>,
+    portability: PortabilityPolicy,
+    // warnings about entries stored anyway, in order
+    warnings: Vec<String>,
+    limiter: Option<Arc<Limiter>>,
+    // held from begin_entry to end_entry
+    permit: Option<Permit>,
+    cancel: Option<Arc<AtomicBool>>,
+    content_transform: Option<ContentTransform>,
+    flush_every: Option<usize>,
+    // entries written since the last flush
+    unflushed: usize,
+    // source path and reason of each entry reported while being written
+    skipped: Vec<(String, ClannadError)>,
+    source_archive: Option<PathBuf>,
+    // opened on the first file read from source_archive
+    source: Option<zip::ZipArchive<File>>,
+}
+
+impl ZipDeflate {
+    /// Also store the full resolution modification, access and creation times of each
+    /// file in an NTFS extra field, as the standard timestamp only keeps 2 seconds.
+    pub fn with_extended_timestamps(mut self, enable: bool) -> Self {
+        self.extended_timestamps = enable;
+        self
+    }
+
+    /// Also store the uid and gid of each file in an Info-ZIP unix extra field, which
+    /// `unzip -X` restores. Files scanned without owner ids get no field.
+    pub fn with_owner(mut self, enable: bool) -> Self {
+        self.owner = enable;
+        self
+    }
+
+    /// Write the CRC-32 of every file's uncompressed content to `path` on `finish`, one
+    /// `<hex>  <name>` line per file in the style of `sha256sum`.
+    pub fn with_checksum_sidecar(mut self, path: &Path) -> Self {
+        self.sidecar = Some(path.to_path_buf());
+        self
+    }
+
+    /// Read the content of regular files from the zip archive at `path` by their
+    /// `content_path`, to repack the entries listed from it by an
+    /// [`ArchiveFilter`](crate::filter::ArchiveFilter). Each file is read into memory
+    /// before it is stored.
+    pub fn with_source_archive(mut self, path: &Path) -> Self {
+        self.source_archive = Some(path.to_path_buf());
+        self
+    }
+
+    /// Record `host` as the system and `version` (major * 10 + minor) as the specification
+    /// version that made every entry, e.g. 0 for MS-DOS, 3 for Unix or 10 for NTFS.
+    ///
+    /// The zip crate writes Unix without a way to change it, so the central directory
+    /// is patched on `finish`. Hosts other than Unix and OS X get DOS attributes in place
+    /// of the unix mode. Archives needing zip64 are left as written.
+    pub fn with_made_by(mut self, host: u8, version: u8) -> Self {
+        self.made_by = Some((host, version));
+        self
+    }
+
+    /// Choose which files are marked as text, none by default.
+    ///
+    /// The zip crate has no way to set the flag, so the central directory is patched on
+    /// `finish` like for [`with_made_by`](Self::with_made_by). The archive file must be
+    /// readable for that, which [`ZipDeflateBuilder::build`] takes care of.
+    pub fn with_text_policy(mut self, policy: TextPolicy) -> Self {
+        self.text_policy = policy;
+        self
+    }
+
+    /// Choose what happens to names Windows cannot extract, warnings by default.
+    pub fn with_portability(mut self, policy: PortabilityPolicy) -> Self {
+        self.portability = policy;
+        self
+    }
+
+    /// Warnings about entries `write_archive` stored anyway, such as names Windows cannot
+    /// extract or sources colliding on a name, in the order they were found. Failed
+    /// writes made outside `write_archive`, which have no entry to be listed under in
+    /// [`skipped`](Self::skipped), are reported here too.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Hold a permit of `limiter` while writing each entry.
+    pub fn with_limiter(mut self, limiter: Arc<Limiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Push the entries written so far out of the writer's buffers to the archive file.
+    ///
+    /// This limits what a crash loses, but the archive is only readable after `finish`
+    /// writes its central directory; the entries of a partial one can only be salvaged
+    /// with recovery tools.
+    pub fn flush(&mut self) -> Result<(), ClannadError> {
+        self.unflushed = 0;
+        Ok(self.writer.flush()?)
+    }
+
+    /// Entries `write_archive` could not store, by source path, in the order they failed.
+    ///
+    /// Under `FailurePolicy::Abort` this holds the entry which aborted the archive. Check
+    /// it before `finish`, which consumes the deflate.
+    pub fn skipped(&self) -> &[(String, ClannadError)] {
+        &self.skipped
+    }
+
+    /// Flush after every `entries` entries written by `write_archive`, see [`flush`](Self::flush).
+    pub fn with_flush_every(mut self, entries: usize) -> Self {
+        self.flush_every = Some(entries.max(1));
+        self
+    }
+
+    /// Stop writing entries once `flag` is set, from any thread.
+    ///
+    /// `finish` then keeps the entries written so far, marks the archive with
+    /// [`CANCELLED_COMMENT`] and returns [`ClannadError::Cancelled`].
+    pub fn with_cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Record `map`, such as a build id, git commit and hostname, in the archive comment
+    /// for [`read_metadata`] to read back.
+    ///
+    /// The comment holds a [`METADATA_HEADER`] line then a `key<TAB>value` line per pair,
+    /// sorted by key, with backslashes, tabs and line breaks escaped. It replaces any
+    /// earlier comment and fails if it would exceed the 64 KiB a zip comment can hold. A
+    /// cancelled archive gets [`CANCELLED_COMMENT`] instead.
+    pub fn set_metadata(&mut self, map: HashMap<String, String>) -> Result<(), ClannadError> {
+        let mut pairs: Vec<_> = map.into_iter().collect();
+        pairs.sort();
+        let mut comment = METADATA_HEADER.to_owned();
+        for (key, value) in pairs {
+            comment.push_str(&format!(
+                "\n{}\t{}",
+                escape_metadata(&key),
+                escape_metadata(&value)
+            ));
+        }
+        if comment.len() > u16::MAX as usize {
+            return Err(ClannadError::InvalidMetadata(format!(
+                "metadata of {} bytes does not fit in the archive comment",
+                comment.len()
+            )));
+        }
+        self.writer.set_comment(comment);
+        Ok(())
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Append the entries to an archive the caller is already writing, with its own
+    /// entries and comment. Use [`finish_into_writer`](Self::finish_into_writer) to get
+    /// it back and finish it.
+    pub fn from_zip_writer(writer: ZipWriter<File>) -> Self {
+        ZipDeflateBuilder::new().build_zip_writer(writer)
+    }
+
+    /// Hand the writer back unfinished, or the first failure under `FailurePolicy::Abort`.
+    pub fn finish_into_writer(self) -> Result<ZipWriter<File>, ClannadError> {
+        match self.failure {
+            Some(failure) => Err(ClannadError::Skipped(failure)),
+            None if self.cancelled() => Err(ClannadError::Cancelled),
+            None => Ok(self.writer),
+        }
+    }
+
+    /// Set the size of the buffer files are streamed through, 64 KiB by default.
+    ///
+    /// Sizes below a few KiB cost a call per chunk and slow down large files, while sizes
+    /// beyond a few MiB only use more memory. A size of 0 is treated as 1.
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Choose what happens to files which are already compressed, deflating them by default.
+    pub fn with_compressed_policy(mut self, policy: CompressedPolicy) -> Self {
+        self.compressed_policy = policy;
+        self
+    }
+
+    /// Store the bytes `transform` returns for each file and its content instead of the
+    /// content itself, for example to encrypt it. Directories and symlinks are untouched.
+    ///
+    /// Files are then read whole into memory rather than streamed.
+    pub fn with_content_transform(mut self, transform: ContentTransform) -> Self {
+        self.content_transform = Some(transform);
+        self
+    }
+
+    /// Stamp each entry with the time `mtime_fn` gives for it instead of the current time.
+    pub fn with_mtime_fn(mut self, mtime_fn: Box<dyn Fn(&FileInfo) -> DateTime>) -> Self {
+        self.mtime_fn = Some(mtime_fn);
+        self
+    }
+
+    /// Choose whether names with `..` components are stripped (default) or rejected.
+    pub fn with_traversal(mut self, policy: TraversalPolicy) -> Self {
+        self.traversal = policy;
+        self
+    }
+
+    /// Choose whether symlinks are stored as symlink entries (default) or resolved to
+    /// what they point to.
+    pub fn with_link_policy(mut self, policy: LinkPolicy) -> Self {
+        self.link_policy = policy;
+        self
+    }
+
+    /// Store every entry by its file name alone, leaving directories out, to collect
+    /// files of a tree into a single folder. See [`with_collision`](Self::with_collision)
+    /// for files sharing a name.
+    pub fn with_flatten(mut self, flatten: bool) -> Self {
+        self.flatten = flatten;
+        self
+    }
+
+    /// Choose what happens to entries whose stored name is taken, skipped by default.
+    pub fn with_collision(mut self, policy: CollisionPolicy) -> Self {
+        self.collision = policy;
+        self
+    }
+
+    /// Add a directory entry for every parent of a stored name missing from the list,
+    /// before the entry itself, for lists built by hand such as `a/b/c.txt` alone.
+    pub fn with_implied_dirs(mut self, enable: bool) -> Self {
+        self.implied_dirs = enable;
+        self
+    }
+
+    /// Store a [`INDEX_ENTRY_NAME`] entry first, the [`index_json`] of the list given to
+    /// `write_archive`, for readers wanting the whole listing up front. Lists written by
+    /// `write_archive_iter` are not known in advance and get no index.
+    pub fn with_index_entry(mut self, enable: bool) -> Self {
+        self.index_entry = enable;
+        self
+    }
+
+    /// Choose whether an entry failure aborts the archive, skipping it by default.
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+
+    /// Choose which part of the scanned root is kept in stored names.
+    pub fn with_base(mut self, base: BaseMode) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Mark files recorded as read-only so extractors restore them read-only.
+    ///
+    /// The flag is stored as unix permissions `0o444` in the external attributes, since
+    /// the `zip` crate does not expose the DOS attribute byte.
+    pub fn with_readonly(mut self, preserve: bool) -> Self {
+        self.preserve_readonly = preserve;
+        self
+    }
+
+    /// Choose the stored permissions, taking precedence over `with_readonly` when forced.
+    pub fn with_permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.permission_policy = policy;
+        self
+    }
+
+    /// Store every entry under the `prefix` folder.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Rewrite stored names ending in `from` to end in `to` instead, such as `.tmpl` to
+    /// nothing to store `index.html.tmpl` as `index.html`.
+    ///
+    /// Only the last component is rewritten and a name consisting of `from` alone is
+    /// kept. Rules apply in the order they were added, each to the result of the ones
+    /// before. A renamed entry colliding with a name already stored is skipped.
+    pub fn with_rename_rule(mut self, from: &str, to: &str) -> Self {
+        self.rename_rules.push((from.to_owned(), to.to_owned()));
+        self
+    }
+
+    /// Set the compression method used when no extension rule matches.
+    ///
+    /// Writing [`CompressionMethod::Deflate64`] is not supported: the `zip` crate can only
+    /// read it, so every entry fails and is reported instead of being stored with another
+    /// method. The same goes for the other methods the crate cannot write.
+    pub fn with_method(mut self, method: CompressionMethod) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Choose the compression method of each file by its extension (without the dot).
+    ///
+    /// ```
+    /// # use clannad::{Deflate, ZipDeflate};
+    /// # use std::{collections::HashMap, path::Path};
+    /// # use zip::CompressionMethod;
+    /// let deflate = ZipDeflate::new(Path::new("doc_extension.zip")).with_extension_methods(
+    ///     HashMap::from([("png".to_owned(), CompressionMethod::Stored)]),
+    /// );
+    /// deflate.finish().unwrap();
+    /// # std::fs::remove_file("doc_extension.zip").unwrap();
+    /// ```
+    pub fn with_extension_methods(mut self, methods: HashMap<String, CompressionMethod>) -> Self {
+        self.extension_methods = methods;
+        self
+    }
+
+    /// Choose the compression method of single entries by their `FileInfo` path, taking
+    /// precedence over the extension rules, as loaded from a [`Manifest`](crate::Manifest).
+    pub fn with_entry_methods(mut self, methods: HashMap<String, CompressionMethod>) -> Self {
+        self.entry_methods = methods;
+        self
+    }
+
+    /// Compress entries using `method` at `level`, such as zstd at 19 next to deflate at 6.
+    /// Methods without a level use the `zip` crate default.
+    ///
+    /// Fails with [`ClannadError::InvalidOption`] when `level` is outside [`level_range`]
+    /// of `method`.
+    pub fn with_level(
+        mut self,
+        method: CompressionMethod,
+        level: i64,
+    ) -> Result<Self, ClannadError> {
+        set_level(&mut self.levels, method, level)?;
+        self.method_options.clear();
+        Ok(self)
+    }
+
+    fn level(&self, method: CompressionMethod) -> Option<i64> {
+        self.levels
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, level)| *level)
+    }
+
+    fn file_options<T: FileOptionExtension>(
+        &self,
+        file: &Path,
+        options: FileOptions<'static, T>,
+    ) -> FileOptions<'static, T> {
+        let method = self.chosen_method(file);
+        let options = apply_method(options, method, self.level(chosen_level_method(method)));
+        self.entry_options(options)
+    }
+
+    /// `file_options` starting from the options of its method, built once per method as
+    /// they are the same for every entry.
+    fn simple_file_options(&mut self, file: &Path) -> SimpleFileOptions {
+        let method = self.chosen_method(file);
+        let level = self.level(chosen_level_method(method));
+        let options = match self.method_options.iter().find(|(m, _)| *m == method) {
+            Some((_, options)) => *options,
+            None => {
+                let options = apply_method(SimpleFileOptions::default(), method, level);
+                self.method_options.push((method, options));
+                options
+            }
+        };
+        self.entry_options(options)
+    }
+
+    /// Compression method of `file`, the zip default if `None`.
+    fn chosen_method(&self, file: &Path) -> Option<CompressionMethod> {
+        let extension = file.extension().and_then(|ext| ext.to_str());
+        let entry_method = self
+            .current
+            .as_ref()
+            .and_then(|info| self.entry_methods.get(&info.path).copied());
+        let method = match self.compressed {
+            true => Some(CompressionMethod::Stored),
+            false => entry_method
+                .or_else(|| extension.and_then(|ext| self.extension_methods.get(ext).copied()))
+                .or(self.method),
+        };
+        #[cfg(feature = "log")]
+        log::debug!(
+            "{}: extension {:?}, already compressed {}, {} chosen",
+            file.display(),
+            extension,
+            self.compressed,
+            method.map_or("default method".to_owned(), |m| format!("{:?}", m))
+        );
+        method
+    }
+
+    /// The options of the current entry itself, its time and permissions.
+    fn entry_options<T: FileOptionExtension>(
+        &self,
+        options: FileOptions<'static, T>,
+    ) -> FileOptions<'static, T> {
+        let options = self.with_mtime(options);
+        match (self.permission_policy, &self.current) {
+            (PermissionPolicy::Force { file, .. }, _) => options.unix_permissions(file),
+            (_, Some(info)) if self.preserve_readonly && info.readonly => {
+                options.unix_permissions(0o444)
+            }
+            _ => options,
+        }
+    }
+
+    /// Start a file entry, with the NTFS times and the owner if enabled.
+    fn start_file(&mut self, file: &Path) -> ZipResult<()> {
+        let times = match (self.extended_timestamps, &self.current) {
+            (true, Some(info)) => ntfs_times(info),
+            _ => None,
+        };
+        let owner = match (self.owner, &self.current) {
+            (true, Some(info)) => unix_owner(info),
+            _ => None,
+        };
+        let extra: Vec<_> = [(NTFS_EXTRA_ID, times), (UNIX_OWNER_EXTRA_ID, owner)]
+            .into_iter()
+            .filter_map(|(id, data)| Some((id, data?)))
+            .collect();
+        match extra.is_empty() {
+            true => {
+                let options = self.simple_file_options(file);
+                self.writer.start_file_from_path(file, options)
+            }
+            false => {
+                let mut options = self.file_options(file, FullFileOptions::default());
+                for (id, data) in extra {
+                    options.add_extra_data(id, data.into_boxed_slice(), false)?;
+                }
+                self.writer.start_file_from_path(file, options)
+            }
+        }
+    }
+
+    /// `path` with the rename rules applied to its last component.
+    fn renamed(&self, path: &Path) -> PathBuf {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return path.to_path_buf();
+        };
+        let name = self
+            .rename_rules
+            .iter()
+            .fold(name.to_owned(), |name, (from, to)| {
+                match name.strip_suffix(from.as_str()) {
+                    Some(stem) if !stem.is_empty() => format!("{}{}", stem, to),
+                    _ => name,
+                }
+            });
+        path.with_file_name(name)
+    }
+
+    fn record_checksum(&mut self, file: &Path, crc: u32) {
+        if self.sidecar.is_some() {
+            let name = dir_entry_name(file).trim_end_matches('/').to_owned();
+            self.checksums.push((name, crc));
+        }
+    }
+
+    /// Mark the file stored as `file` as text if `head`, its first bytes, qualify.
+    fn record_text(&mut self, file: &Path, head: &[u8]) {
+        let text = match self.text_policy {
+            TextPolicy::Detect => looks_like_text(&head[..head.len().min(TEXT_SNIFF_LEN)]),
+            TextPolicy::Always => true,
+            TextPolicy::Never => false,
+        };
+        if text {
+            let name = dir_entry_name(file).trim_end_matches('/').to_owned();
+            self.text_entries.insert(name);
+        }
+    }
+
+    fn with_mtime<T: FileOptionExtension>(
+        &self,
+        options: FileOptions<'static, T>,
+    ) -> FileOptions<'static, T> {
+        match (&self.mtime_fn, &self.current) {
+            (Some(mtime_fn), Some(info)) => options.last_modified_time(mtime_fn(info)),
+            _ => options,
+        }
+    }
+}
+
+/// NTFS extra field holding the modification, access and creation times of the current
+/// entry, in 100 ns ticks since 1601. Unknown times are left 0.
+fn ntfs_times(info: &FileInfo) -> Option<Vec<u8>> {
+    let metadata = fs::metadata(&info.content_path).ok()?;
+    let ticks = |time: io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| NTFS_UNIX_EPOCH + (d.as_nanos() / 100) as u64)
+    };
+    let mut data = Vec::with_capacity(32);
+    data.extend(0u32.to_le_bytes());
+    // attribute tag 1 and size of the three times
+    data.extend(1u16.to_le_bytes());
+    data.extend(24u16.to_le_bytes());
+    [metadata.modified(), metadata.accessed(), metadata.created()]
+        .into_iter()
+        .for_each(|time| data.extend(ticks(time).to_le_bytes()));
+    Some(data)
+}
+
+/// Info-ZIP unix extra field holding the uid and gid of the current entry as 4 bytes each,
+/// `None` if they were not scanned.
+fn unix_owner(info: &FileInfo) -> Option<Vec<u8>> {
+    let (uid, gid) = (info.uid?, info.gid?);
+    let mut data = Vec::with_capacity(11);
+    // version 1, then the size and value of each id
+    data.push(1);
+    data.push(4);
+    data.extend(uid.to_le_bytes());
+    data.push(4);
+    data.extend(gid.to_le_bytes());
+    Some(data)
+}
+
+/// Hosts whose external attributes carry a unix mode in their high 16 bits: Unix and
+/// OS X.
+const UNIX_HOSTS: [u8; 2] = [3, 19];
+
+/// External attributes of a central directory `header` for a host reading DOS attributes,
+/// the directory and read-only bits taken from the unix mode the zip crate wrote.
+fn dos_attributes(name: &str, header: &[u8]) -> [u8; 4] {
+    let mode = u16::from_le_bytes([header[40], header[41]]);
+    let mut attributes = 0;
+    if name.ends_with('/') {
+        attributes |= 0x10;
+    }
+    if mode != 0 && mode & 0o200 == 0 {
+        attributes |= 0x01;
+    }
+    [attributes, 0, 0, 0]
+}
+
+/// Let `patch` rewrite the fixed 46 bytes of every central directory header of a
+/// finished archive, given the entry name. Archives using zip64 end records are not
+/// touched.
+fn patch_central_directory(
+    file: &mut File,
+    mut patch: impl FnMut(&str, &mut [u8]),
+) -> io::Result<()> {
+    let u16_at = |b: &[u8], at: usize| usize::from(u16::from_le_bytes([b[at], b[at + 1]]));
+    let u32_at = |b: &[u8], at: usize| u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]);
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed central directory");
+
+    // the end record is 22 bytes followed by a comment of at most 64 KiB
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(22 + 0xffff)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let end = tail
+        .windows(4)
+        .rposition(|w| w == b"PK\x05\x06")
+        .filter(|&end| tail.len() >= end + 22)
+        .ok_or_else(invalid)?;
+    let entries = u16_at(&tail, end + 10);
+    let (size, offset) = (u32_at(&tail, end + 12), u32_at(&tail, end + 16));
+    if entries == 0xffff || size == u32::MAX || offset == u32::MAX {
+        return Ok(());
+    }
+
+    let mut central = vec![0; size as usize];
+    file.seek(SeekFrom::Start(offset.into()))?;
+    file.read_exact(&mut central)?;
+    let mut at = 0;
+    for _ in 0..entries {
+        if central.len() < at + 46 || &central[at..at + 4] != b"PK\x01\x02" {
+            return Err(invalid());
+        }
+        let name_len = u16_at(&central, at + 28);
+        let name = central
+            .get(at + 46..at + 46 + name_len)
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .ok_or_else(invalid)?;
+        patch(&name, &mut central[at..at + 46]);
+        at += 46 + name_len + u16_at(&central, at + 30) + u16_at(&central, at + 32);
+    }
+    file.seek(SeekFrom::Start(offset.into()))?;
+    file.write_all(&central)
+}
+
+/// Collect the options of a [`ZipDeflate`] before creating the archive.
+///
+/// ```
+/// # use clannad::{BaseMode, Deflate, ZipDeflateBuilder};
+/// # use std::path::Path;
+/// let deflate = ZipDeflateBuilder::new()
+///     .prefix("release")
+///     .base(BaseMode::ExcludeRoot)
+///     .build(Path::new("doc_builder.zip"))
+///     .unwrap();
+/// deflate.finish().unwrap();
+/// # std::fs::remove_file("doc_builder.zip").unwrap();
+/// ```
+pub struct ZipDeflateBuilder {
+    method: Option<CompressionMethod>,
+    extension_methods: HashMap<String, CompressionMethod>,
+    // methods of single entries by path, before the extension rules
+    entry_methods: HashMap<String, CompressionMethod>,
+    // compression level chosen for each method
+    levels: Vec<(CompressionMethod, i64)>,
+    prefix: Option<String>,
+    // suffix rewrites of stored file names, applied in order
+    rename_rules: Vec<(String, String)>,
+    base: BaseMode,
+    preserve_readonly: bool,
+    permission_policy: PermissionPolicy,
+    traversal: TraversalPolicy,
+    link_policy: LinkPolicy,
+    flatten: bool,
+    collision: CollisionPolicy,
+    implied_dirs: bool,
+    index_entry: bool,
+    failure_policy: FailurePolicy,
+    mtime_fn: Option<MtimeFn>,
+    compressed_policy: CompressedPolicy,
+    buffer_size: usize,
+    extended_timestamps: bool,
+    owner: bool,
+    sidecar: Option<PathBuf>,
+    made_by: Option<(u8, u8)>,
+    text_policy: TextPolicy,
+    portability: PortabilityPolicy,
+    limiter: Option<Arc<Limiter>>,
+    cancel: Option<Arc<AtomicBool>>,
+    content_transform: Option<ContentTransform>,
+    flush_every: Option<usize>,
+    source_archive: Option<PathBuf>,
+}
+
+impl Default for ZipDeflateBuilder {
+    fn default() -> Self {
+        Self {
+            method: None,
+            extension_methods: HashMap::new(),
+            entry_methods: HashMap::new(),
+            levels: Vec::new(),
+            prefix: None,
+            rename_rules: Vec::new(),
+            base: BaseMode::IncludeRoot,
+            preserve_readonly: false,
+            permission_policy: PermissionPolicy::Default,
+            traversal: TraversalPolicy::Strip,
+            link_policy: LinkPolicy::Store,
+            flatten: false,
+            collision: CollisionPolicy::Skip,
+            implied_dirs: false,
+            index_entry: false,
+            failure_policy: FailurePolicy::SkipWithWarning,
+            mtime_fn: None,
+            compressed_policy: CompressedPolicy::Deflate,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            extended_timestamps: false,
+            owner: false,
+            sidecar: None,
+            made_by: None,
+            text_policy: TextPolicy::Never,
+            portability: PortabilityPolicy::Warn,
+            limiter: None,
+            cancel: None,
+            content_transform: None,
+            flush_every: None,
+            source_archive: None,
+        }
+    }
+}
+
+impl ZipDeflateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`ZipDeflate::with_method`].
+    pub fn method(mut self, method: CompressionMethod) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// See [`ZipDeflate::with_extension_methods`].
+    pub fn extension_methods(mut self, methods: HashMap<String, CompressionMethod>) -> Self {
+        self.extension_methods = methods;
+        self
+    }
+
+    /// See [`ZipDeflate::with_entry_methods`].
+    pub fn entry_methods(mut self, methods: HashMap<String, CompressionMethod>) -> Self {
+        self.entry_methods = methods;
+        self
+    }
+
+    /// See [`ZipDeflate::with_level`].
+    pub fn level(mut self, method: CompressionMethod, level: i64) -> Result<Self, ClannadError> {
+        set_level(&mut self.levels, method, level)?;
+        Ok(self)
+    }
+
+    /// See [`ZipDeflate::with_prefix`].
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// See [`ZipDeflate::with_rename_rule`].
+    pub fn rename_rule(mut self, from: &str, to: &str) -> Self {
+        self.rename_rules.push((from.to_owned(), to.to_owned()));
+        self
+    }
+
+    /// See [`ZipDeflate::with_base`].
+    pub fn base(mut self, base: BaseMode) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// See [`ZipDeflate::with_readonly`].
+    pub fn readonly(mut self, preserve: bool) -> Self {
+        self.preserve_readonly = preserve;
+        self
+    }
+
+    /// See [`ZipDeflate::with_permission_policy`].
+    pub fn permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.permission_policy = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_traversal`].
+    pub fn traversal(mut self, policy: TraversalPolicy) -> Self {
+        self.traversal = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_link_policy`].
+    pub fn link_policy(mut self, policy: LinkPolicy) -> Self {
+        self.link_policy = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_flatten`].
+    pub fn flatten(mut self, flatten: bool) -> Self {
+        self.flatten = flatten;
+        self
+    }
+
+    /// See [`ZipDeflate::with_collision`].
+    pub fn collision(mut self, policy: CollisionPolicy) -> Self {
+        self.collision = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_implied_dirs`].
+    pub fn implied_dirs(mut self, enable: bool) -> Self {
+        self.implied_dirs = enable;
+        self
+    }
+
+    /// See [`ZipDeflate::with_index_entry`].
+    pub fn index_entry(mut self, enable: bool) -> Self {
+        self.index_entry = enable;
+        self
+    }
+
+    /// See [`ZipDeflate::with_failure_policy`].
+    pub fn failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_content_transform`].
+    pub fn content_transform(mut self, transform: ContentTransform) -> Self {
+        self.content_transform = Some(transform);
+        self
+    }
+
+    /// See [`ZipDeflate::with_mtime_fn`].
+    pub fn mtime_fn(mut self, mtime_fn: Box<dyn Fn(&FileInfo) -> DateTime>) -> Self {
+        self.mtime_fn = Some(mtime_fn);
+        self
+    }
+
+    /// See [`ZipDeflate::with_compressed_policy`].
+    pub fn compressed_policy(mut self, policy: CompressedPolicy) -> Self {
+        self.compressed_policy = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_buffer_size`].
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// See [`ZipDeflate::with_extended_timestamps`].
+    pub fn extended_timestamps(mut self, enable: bool) -> Self {
+        self.extended_timestamps = enable;
+        self
+    }
+
+    /// See [`ZipDeflate::with_owner`].
+    pub fn owner(mut self, enable: bool) -> Self {
+        self.owner = enable;
+        self
+    }
+
+    /// See [`ZipDeflate::with_checksum_sidecar`].
+    pub fn checksum_sidecar(mut self, path: &Path) -> Self {
+        self.sidecar = Some(path.to_path_buf());
+        self
+    }
+
+    /// See [`ZipDeflate::with_source_archive`].
+    pub fn source_archive(mut self, path: &Path) -> Self {
+        self.source_archive = Some(path.to_path_buf());
+        self
+    }
+
+    /// See [`ZipDeflate::with_made_by`].
+    pub fn made_by(mut self, host: u8, version: u8) -> Self {
+        self.made_by = Some((host, version));
+        self
+    }
+
+    /// See [`ZipDeflate::with_text_policy`].
+    pub fn text_policy(mut self, policy: TextPolicy) -> Self {
+        self.text_policy = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_portability`].
+    pub fn portability(mut self, policy: PortabilityPolicy) -> Self {
+        self.portability = policy;
+        self
+    }
+
+    /// See [`ZipDeflate::with_limiter`].
+    pub fn limiter(mut self, limiter: Arc<Limiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// See [`ZipDeflate::with_flush_every`].
+    pub fn flush_every(mut self, entries: usize) -> Self {
+        self.flush_every = Some(entries.max(1));
+        self
+    }
+
+    /// See [`ZipDeflate::with_cancel_flag`].
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Create the archive file at `path`.
+    ///
+    /// The file is opened for reading too, as copying entries and patching the central
+    /// directory read back what was written. When `path` lies within a scanned root, the
+    /// archive is left out of what `write_archive` stores.
+    pub fn build(self, path: &Path) -> Result<ZipDeflate, ClannadError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut deflate = self.build_writer(file);
+        deflate.path = Some(path.to_path_buf());
+        Ok(deflate)
+    }
+
+    /// Write the archive into an already opened file.
+    ///
+    /// Unlike [`build`](Self::build), the file is left in place when the archive is aborted.
+    pub fn build_writer(self, file: File) -> ZipDeflate {
+        self.build_zip_writer(ZipWriter::new(file))
+    }
+
+    /// Append to an archive the caller is already writing, see [`ZipDeflate::from_zip_writer`].
+    pub fn build_zip_writer(self, writer: ZipWriter<File>) -> ZipDeflate {
+        ZipDeflate {
+            writer,
+            path: None,
+            method: self.method,
+            extension_methods: self.extension_methods,
+            entry_methods: self.entry_methods,
+            levels: self.levels,
+            prefix: self.prefix,
+            rename_rules: self.rename_rules,
+            base: self.base,
+            preserve_readonly: self.preserve_readonly,
+            permission_policy: self.permission_policy,
+            traversal: self.traversal,
+            link_policy: self.link_policy,
+            flatten: self.flatten,
+            collision: self.collision,
+            implied_dirs: self.implied_dirs,
+            index_entry: self.index_entry,
+            current: None,
+            failure_policy: self.failure_policy,
+            failure: None,
+            mtime_fn: self.mtime_fn,
+            compressed_policy: self.compressed_policy,
+            compressed: false,
+            buffer_size: self.buffer_size,
+            buffer: Vec::new(),
+            method_options: Vec::new(),
+            extended_timestamps: self.extended_timestamps,
+            owner: self.owner,
+            sidecar: self.sidecar,
+            checksums: Vec::new(),
+            made_by: self.made_by,
+            text_policy: self.text_policy,
+            text_entries: HashSet::new(),
+            portability: self.portability,
+            warnings: Vec::new(),
+            limiter: self.limiter,
+            permit: None,
+            cancel: self.cancel,
+            content_transform: self.content_transform,
+            flush_every: self.flush_every,
+            unflushed: 0,
+            skipped: Vec::new(),
+            source_archive: self.source_archive,
+            source: None,
+        }
+    }
 }
 
 impl Deflate for ZipDeflate {
     fn new(path: &Path) -> Self {
-        Self {
-            writer: ZipWriter::new(File::create(path).expect("archive file is not valid")),
+        ZipDeflateBuilder::new()
+            .build(path)
+            .expect("archive file is not valid")
+    }
+
+    fn begin_entry(&mut self, info: &FileInfo) {
+        self.permit = self.limiter.as_ref().map(|l| l.acquire());
+        self.current = Some(info.clone());
+    }
+
+    fn destination(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn portability(&self) -> PortabilityPolicy {
+        self.portability
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    fn end_entry(&mut self) {
+        self.current = None;
+        self.compressed = false;
+        self.permit = None;
+        self.unflushed += 1;
+        if self
+            .flush_every
+            .is_some_and(|every| self.unflushed >= every)
+        {
+            if let Err(e) = self.flush() {
+                self.report(format!("cannot flush the archive: {}", e));
+            }
         }
     }
 
-    fn finish(self) -> Result<(), Box<dyn Error>> {
-        self.writer.finish()?;
-        Ok(())
+    fn sniff(&mut self, head: &[u8]) -> bool {
+        if self.compressed_policy == CompressedPolicy::Deflate {
+            return true;
+        }
+        self.compressed = is_compressed(head);
+        match (self.compressed, self.compressed_policy, &self.current) {
+            (true, CompressedPolicy::Skip, Some(info)) => {
+                let message = format!("{} is already compressed, skipped", info.content_path);
+                self.warn(message);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn read_content(&mut self, f: &FileInfo) -> Option<io::Result<Vec<u8>>> {
+        let path = self.source_archive.as_ref()?;
+        if self.source.is_none() {
+            match File::open(path).and_then(|file| Ok(zip::ZipArchive::new(file)?)) {
+                Ok(archive) => self.source = Some(archive),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let archive = self.source.as_mut()?;
+        let mut content = Vec::new();
+        Some(
+            archive
+                .by_name(&f.content_path)
+                .map_err(io::Error::from)
+                .and_then(|mut entry| entry.read_to_end(&mut content))
+                .map(|_| content),
+        )
+    }
+
+    fn base(&self) -> BaseMode {
+        self.base
+    }
+
+    fn traversal(&self) -> TraversalPolicy {
+        self.traversal
+    }
+
+    fn flatten(&self) -> bool {
+        self.flatten
+    }
+
+    fn collision(&self) -> CollisionPolicy {
+        self.collision
+    }
+
+    fn implied_dirs(&self) -> bool {
+        self.implied_dirs
+    }
+
+    fn index_entry(&self) -> bool {
+        self.index_entry
+    }
+
+    fn entry_name(&self, path: &Path) -> PathBuf {
+        let path = self.renamed(path);
+        let path = match (self.flatten, path.file_name()) {
+            (true, Some(name)) => PathBuf::from(name),
+            _ => path,
+        };
+        match &self.prefix {
+            Some(prefix) => Path::new(prefix).join(path.strip_prefix("/").unwrap_or(&path)),
+            None => path,
+        }
+    }
+
+    fn finish(mut self) -> Result<(), ClannadError> {
+        let cancelled = self.failure.is_none() && self.cancelled();
+        if cancelled {
+            self.writer.set_comment(CANCELLED_COMMENT);
+        }
+        let mut file = self.writer.finish()?;
+        let patched = self.made_by.is_some() || !self.text_entries.is_empty();
+        if patched && self.failure.is_none() {
+            let (made_by, text_entries) = (self.made_by, &self.text_entries);
+            patch_central_directory(&mut file, |name, header| {
+                if let Some((host, version)) = made_by {
+                    header[4..6].copy_from_slice(&[version, host]);
+                    if !UNIX_HOSTS.contains(&host) {
+                        let attributes = dos_attributes(name, header);
+                        header[38..42].copy_from_slice(&attributes);
+                    }
+                }
+                // bit 0 of the internal file attributes marks text
+                if text_entries.contains(name.trim_end_matches('/')) {
+                    header[36] |= 1;
+                }
+            })?;
+        }
+        match self.failure {
+            Some(failure) => {
+                if let Some(path) = &self.path {
+                    fs::remove_file(path)?;
+                }
+                Err(ClannadError::Skipped(failure))
+            }
+            None if cancelled => Err(ClannadError::Cancelled),
+            None => {
+                if let Some(sidecar) = &self.sidecar {
+                    let lines: String = self
+                        .checksums
+                        .iter()
+                        .map(|(name, crc)| format!("{:08x}  {}\n", crc, name))
+                        .collect();
+                    fs::write(sidecar, lines)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn report(&mut self, message: String) {
+        match &self.current {
+            Some(info) => self
+                .skipped
+                .push((info.path.clone(), ClannadError::Skipped(message.clone()))),
+            // outside write_archive there is no entry to list it under
+            None => self.warnings.push(message.clone()),
+        }
+        match self.failure_policy {
+            FailurePolicy::SkipWithWarning => {}
+            FailurePolicy::Abort => {
+                if self.failure.is_none() {
+                    self.failure = Some(message);
+                }
+            }
+        }
+    }
+
+    fn aborted(&self) -> bool {
+        self.failure.is_some() || self.cancelled()
     }
 
     fn write_dir(&mut self, dir: &Path) {
-        match self
-            .writer
-            .add_directory_from_path(dir, SimpleFileOptions::default())
-        {
+        let options = self.with_mtime(SimpleFileOptions::default());
+        let options = match self.permission_policy {
+            PermissionPolicy::Force { dir, .. } => options.unix_permissions(dir),
+            PermissionPolicy::Default => options,
+        };
+        match self.writer.add_directory(dir_entry_name(dir), options) {
             Ok(_) => {}
-            Err(_) => println!("{}", format!("{} is illegal dir", dir.to_str().unwrap())),
+            Err(_) => self.report(format!("{} is illegal dir", dir.to_str().unwrap())),
         }
     }
 
     fn write_file(&mut self, file: &Path, content: &[u8]) {
-        match self
-            .writer
-            .start_file_from_path(file, SimpleFileOptions::default())
-        {
-            Ok(_) => {}
-            Err(_) => println!("{}", format!("{} is illegal path", file.to_str().unwrap())),
+        if self.start_file(file).is_err() {
+            self.report(format!("{} is illegal path", file.to_str().unwrap()));
+            return;
         }
         match self.writer.write_all(content) {
-            Ok(_) => {}
-            Err(_) => println!("{}", format!("{} is illegal file", file.to_str().unwrap())),
+            Ok(_) => {
+                self.record_checksum(file, crc32fast::hash(content));
+                self.record_text(file, content);
+            }
+            Err(_) => {
+                // drop the partial entry rather than leave it in the archive
+                let _ = self.writer.abort_file();
+                self.report(format!("{} is illegal file", file.to_str().unwrap()));
+            }
         };
     }
 
+    fn write_file_streaming(&mut self, file: &Path, reader: &mut dyn Read) -> u64 {
+        if let (Some(transform), Some(info)) = (&self.content_transform, &self.current) {
+            let mut content = Vec::new();
+            let transformed = reader
+                .read_to_end(&mut content)
+                .map(|_| transform(info, &content));
+            return match transformed {
+                Ok(transformed) => {
+                    self.write_file(file, &transformed);
+                    content.len() as u64
+                }
+                Err(_) => {
+                    self.report(format!("{} is illegal file", file.to_str().unwrap()));
+                    0
+                }
+            };
+        }
+        let started = self.start_file(file);
+        if started.is_err() {
+            self.report(format!("{} is illegal path", file.to_str().unwrap()));
+            return 0;
+        }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(self.buffer_size.max(1), 0);
+        let mut written = 0;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut head = Vec::new();
+        let complete = loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break true,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break false,
+            };
+            if self.writer.write_all(&buffer[..read]).is_err() {
+                break false;
+            }
+            hasher.update(&buffer[..read]);
+            let missing = TEXT_SNIFF_LEN.saturating_sub(head.len());
+            head.extend_from_slice(&buffer[..read.min(missing)]);
+            written += read as u64;
+        };
+        self.buffer = buffer;
+        if !complete {
+            let _ = self.writer.abort_file();
+            self.report(format!("{} is illegal file", file.to_str().unwrap()));
+            return 0;
+        }
+        self.record_checksum(file, hasher.finalize());
+        self.record_text(file, &head);
+        written
+    }
+
     fn write_symlink(&mut self, link: &Path, target: &Path) {
-        match self
-            .writer
-            .add_symlink_from_path(link, target, SimpleFileOptions::default())
-        {
+        if let (LinkPolicy::Resolve, Some(info)) = (self.link_policy, &self.current) {
+            let resolution = filter::resolve_symlink(Path::new(&info.content_path));
+            if !resolution.exists {
+                self.report(format!("{} is a broken symlink", link.to_str().unwrap()));
+            } else if resolution.target.is_dir() {
+                self.write_dir(link);
+            } else {
+                match File::open(&resolution.target) {
+                    Ok(mut content) => {
+                        self.write_file_streaming(link, &mut content);
+                    }
+                    Err(_) => self.report(format!("{} is not valid", info.content_path)),
+                }
+            }
+            return;
+        }
+        let options = self.with_mtime(SimpleFileOptions::default());
+        match self.writer.add_symlink_from_path(link, target, options) {
             Ok(_) => {}
-            Err(_) => println!(
-                "{}",
-                format!("{} is illegal symlink", link.to_str().unwrap())
-            ),
+            Err(_) => self.report(format!("{} is illegal symlink", link.to_str().unwrap())),
         }
     }
 
-    fn copy_dir(&mut self, src: &Path, dest: &Path) {
-        match self.writer.deep_copy_file_from_path(src, dest) {
-            Ok(_) => {}
-            Err(_) => println!(
-                "{}",
-                format!(
-                    "cannot copy {} to {}",
-                    src.to_str().unwrap(),
-                    dest.to_str().unwrap()
-                )
-            ),
+    fn copy_entry(&mut self, from_name: &str, to_name: &str) -> Result<(), ClannadError> {
+        match self.writer.deep_copy_file(from_name, to_name) {
+            Ok(_) => Ok(()),
+            Err(ZipError::FileNotFound) => Err(ClannadError::InvalidPath(format!(
+                "{} is not in the archive yet",
+                from_name
+            ))),
+            Err(e) => Err(e.into()),
         }
     }
 }
@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use zip::result::ZipError;
+
+/// Errors returned by the archiving API.
+#[derive(Debug)]
+pub enum ClannadError {
+    /// Reading the sources or writing the archive failed.
+    Io(io::Error),
+    /// The `zip` crate refused an operation.
+    Zip(ZipError),
+    /// A path or stored name cannot be used, such as an entry missing from the archive.
+    InvalidPath(String),
+    /// A chain of symlinks never reaches a file.
+    Cycle(String),
+    /// An entry could not be archived while failures abort the archive.
+    Skipped(String),
+    /// The cancel flag was raised before every entry was written.
+    Cancelled,
+    /// A setting is out of range, such as a compression level the method does not accept.
+    InvalidOption(String),
+    /// Archive metadata cannot be stored or read back, see
+    /// [`ZipDeflate::set_metadata`](crate::ZipDeflate::set_metadata).
+    InvalidMetadata(String),
+}
+
+impl fmt::Display for ClannadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClannadError::Io(e) => write!(f, "{}", e),
+            ClannadError::Zip(e) => write!(f, "{}", e),
+            ClannadError::InvalidPath(message)
+            | ClannadError::Cycle(message)
+            | ClannadError::Skipped(message)
+            | ClannadError::InvalidOption(message)
+            | ClannadError::InvalidMetadata(message) => write!(f, "{}", message),
+            ClannadError::Cancelled => write!(f, "archive was cancelled"),
+        }
+    }
+}
+
+impl Error for ClannadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClannadError::Io(e) => Some(e),
+            ClannadError::Zip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClannadError {
+    fn from(e: io::Error) -> Self {
+        ClannadError::Io(e)
+    }
+}
+
+impl From<ZipError> for ClannadError {
+    fn from(e: ZipError) -> Self {
+        ClannadError::Zip(e)
+    }
+}
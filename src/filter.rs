@@ -1,5 +1,25 @@
-use std::{collections::VecDeque, fs, path::Path, vec::IntoIter};
+use crate::limit::Limiter;
+#[cfg(feature = "regex")]
+use regex::Regex;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env, fmt,
+    fs::{self, File, Metadata},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::SyncSender,
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+    vec::IntoIter,
+};
+use zip::ZipArchive;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FileType {
     REGULAR,
     DIRECTORY,
@@ -7,6 +27,48 @@ pub enum FileType {
     NONE,
 }
 
+impl FileType {
+    /// Classify `metadata`, `is_symlink` telling whether it was read through a symlink.
+    ///
+    /// Special files such as fifos, sockets and devices are `NONE`.
+    pub fn from_metadata(metadata: &Metadata, is_symlink: bool) -> Self {
+        if is_symlink || metadata.file_type().is_symlink() {
+            FileType::SYMLINK
+        } else if metadata.is_dir() {
+            FileType::DIRECTORY
+        } else if metadata.is_file() {
+            FileType::REGULAR
+        } else {
+            FileType::NONE
+        }
+    }
+
+    /// Classify `path` itself without following it, `NONE` if it does not exist.
+    pub fn from_path(path: &Path) -> Self {
+        lstat(path).map_or(FileType::NONE, |m| Self::from_metadata(&m, false))
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            FileType::REGULAR => "REGULAR",
+            FileType::DIRECTORY => "DIRECTORY",
+            FileType::SYMLINK => "SYMLINK",
+            FileType::NONE => "NONE",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "REGULAR" => Some(FileType::REGULAR),
+            "DIRECTORY" => Some(FileType::DIRECTORY),
+            "SYMLINK" => Some(FileType::SYMLINK),
+            "NONE" => Some(FileType::NONE),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileInfo {
     // filesystem path
     pub path: String,
@@ -16,6 +78,19 @@ pub struct FileInfo {
     pub symlink_path: Option<String>,
     //file type
     pub file_type: FileType,
+    // size in bytes of regular file content when scanned
+    pub size: u64,
+    // whether the content lacks write permission
+    pub readonly: bool,
+    // owner ids, of the symlink itself for retained symlinks
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // canonical absolute path, only recorded on request
+    pub abs_path: Option<String>,
+    // BSD file flags such as `uchg`, only known on macOS and FreeBSD
+    pub flags: Option<u32>,
+    // mount point holding the entry, only recorded on request and on Linux
+    pub mount_point: Option<String>,
 }
 
 impl FileInfo {
@@ -25,12 +100,224 @@ impl FileInfo {
         file_type: FileType,
         symlink_path: Option<&Path>,
     ) -> Self {
+        let metadata = match symlink_path {
+            None => stat(content_path).ok(),
+            Some(_) => lstat(content_path).ok(),
+        };
+        Self::with_metadata(
+            path,
+            content_path,
+            file_type,
+            symlink_path,
+            metadata.as_ref(),
+        )
+    }
+
+    /// `new` with the metadata of `content_path` already read, of the symlink itself when
+    /// `symlink_path` is set, so the scan reads each entry once.
+    fn with_metadata(
+        path: &Path,
+        content_path: &Path,
+        file_type: FileType,
+        symlink_path: Option<&Path>,
+        metadata: Option<&Metadata>,
+    ) -> Self {
+        let (metadata, link_metadata) = match symlink_path {
+            None => (metadata, None),
+            Some(_) => (None, metadata),
+        };
+        let size = match (&file_type, metadata) {
+            (FileType::REGULAR, Some(metadata)) => metadata.len(),
+            _ => 0,
+        };
+        let (uid, gid) = ownership(metadata.or(link_metadata));
         Self {
-            path: path.to_str().expect("invalid path").to_owned(),
-            content_path: content_path.to_str().expect("invalid path").to_owned(),
+            path: normalize(path),
+            content_path: normalize(content_path),
             file_type,
             symlink_path: symlink_path.map(|p| p.to_str().expect("invalid path").to_owned()),
+            size,
+            readonly: metadata.is_some_and(|m| m.permissions().readonly()),
+            uid,
+            gid,
+            abs_path: None,
+            flags: file_flags(metadata.or(link_metadata)),
+            mount_point: None,
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // metadata reads by `stat` and `lstat` on this thread
+    static METADATA_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// `fs::metadata`, the one place a scan reads followed metadata.
+fn stat(path: &Path) -> io::Result<Metadata> {
+    #[cfg(test)]
+    METADATA_CALLS.with(|calls| calls.set(calls.get() + 1));
+    fs::metadata(path)
+}
+
+/// `fs::symlink_metadata`, the one place a scan reads metadata without following links.
+fn lstat(path: &Path) -> io::Result<Metadata> {
+    #[cfg(test)]
+    METADATA_CALLS.with(|calls| calls.set(calls.get() + 1));
+    fs::symlink_metadata(path)
+}
+
+/// `path` with repeated separators and `.` components collapsed, `a//b/./c` as `a/b/c`.
+///
+/// Purely lexical: `..` is kept as resolving it would need to follow symlinks.
+fn normalize(path: &Path) -> String {
+    path.components()
+        .collect::<PathBuf>()
+        .to_str()
+        .expect("invalid path")
+        .to_owned()
+}
+
+/// Owner user and group ids, unknown off unix.
+fn ownership(metadata: Option<&Metadata>) -> (Option<u32>, Option<u32>) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.map_or((None, None), |m| (Some(m.uid()), Some(m.gid())))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        (None, None)
+    }
+}
+
+/// BSD file flags, unknown on other platforms.
+fn file_flags(metadata: Option<&Metadata>) -> Option<u32> {
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::macos::fs::MetadataExt;
+        metadata.map(|m| m.st_flags())
+    }
+    #[cfg(target_os = "freebsd")]
+    {
+        use std::os::freebsd::fs::MetadataExt;
+        metadata.map(|m| m.st_flags())
+    }
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// State of an unfinished scan: paths still queued and entries found so far.
+///
+/// Saved with NUL separated fields, so any UTF-8 file name survives a round trip. Entries
+/// keep every recorded field, `None` being saved as an empty field, so loading does not
+/// read the filesystem again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanCheckpoint {
+    pub queue: VecDeque<String>,
+    pub results: Vec<FileInfo>,
+}
+
+impl ScanCheckpoint {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut content = String::new();
+        self.queue
+            .iter()
+            .for_each(|p| content.extend(["Q\0", p.as_str(), "\0"]));
+        self.results.iter().for_each(|f| {
+            match &f.symlink_path {
+                Some(points_to) => content.extend(["L\0", points_to.as_str(), "\0"]),
+                None => content.push_str("R\0"),
+            };
+            content.extend([f.path.as_str(), "\0", f.content_path.as_str(), "\0"]);
+            content.extend([f.file_type.as_str(), "\0"]);
+            let number = |n: Option<u32>| n.map_or_else(String::new, |n| n.to_string());
+            [
+                f.size.to_string(),
+                u8::from(f.readonly).to_string(),
+                number(f.uid),
+                number(f.gid),
+                f.abs_path.clone().unwrap_or_default(),
+                number(f.flags),
+                f.mount_point.clone().unwrap_or_default(),
+            ]
+            .iter()
+            .for_each(|field| content.extend([field.as_str(), "\0"]));
+        });
+        fs::write(path, content)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid scan checkpoint");
+        let content = fs::read_to_string(path)?;
+        let mut fields = content.split_terminator('\0');
+        let mut checkpoint = Self::default();
+        while let Some(tag) = fields.next() {
+            let mut next = || fields.next().ok_or_else(invalid);
+            match tag {
+                "Q" => checkpoint.queue.push_back(next()?.to_owned()),
+                "R" | "L" => {
+                    let symlink_path = if tag == "L" { Some(next()?) } else { None };
+                    checkpoint.results.push(FileInfo {
+                        symlink_path: symlink_path.map(|p| p.to_owned()),
+                        path: next()?.to_owned(),
+                        content_path: next()?.to_owned(),
+                        file_type: FileType::from_name(next()?).ok_or_else(invalid)?,
+                        size: next()?.parse().map_err(|_| invalid())?,
+                        readonly: match next()? {
+                            "0" => false,
+                            "1" => true,
+                            _ => return Err(invalid()),
+                        },
+                        uid: parse_optional(next()?).ok_or_else(invalid)?,
+                        gid: parse_optional(next()?).ok_or_else(invalid)?,
+                        abs_path: parse_optional(next()?).ok_or_else(invalid)?,
+                        flags: parse_optional(next()?).ok_or_else(invalid)?,
+                        mount_point: parse_optional(next()?).ok_or_else(invalid)?,
+                    });
+                }
+                _ => return Err(invalid()),
+            }
         }
+        Ok(checkpoint)
+    }
+}
+
+/// Optional field of a saved checkpoint, empty for `None`; `None` if it does not parse.
+fn parse_optional<T: FromStr>(field: &str) -> Option<Option<T>> {
+    match field {
+        "" => Some(None),
+        field => field.parse().ok().map(Some),
+    }
+}
+
+/// Nested view of a scan result, keyed by path component.
+///
+/// `info` is `None` for intermediate directories that were not part of the scan.
+#[derive(Clone, Debug, Default)]
+pub struct DirNode {
+    pub info: Option<FileInfo>,
+    pub children: BTreeMap<String, DirNode>,
+}
+
+impl DirNode {
+    fn insert(&mut self, relative: &Path, info: FileInfo) {
+        let node = relative.components().fold(self, |node, component| {
+            node.children
+                .entry(
+                    component
+                        .as_os_str()
+                        .to_str()
+                        .expect("invalid path")
+                        .to_owned(),
+                )
+                .or_default()
+        });
+        node.info = Some(info);
     }
 }
 
@@ -39,6 +326,156 @@ pub trait Filter: IntoIterator<Item = FileInfo> {
     fn scan(&mut self);
     fn files(&self) -> &Option<Vec<FileInfo>>;
     fn update(self, root: &Path) -> Self;
+    /// Settings changed by the provided setters such as [`exclude`](Self::exclude), for
+    /// the scans of the filter to apply.
+    fn options_mut(&mut self) -> &mut ScanOptions;
+    /// Keep only the scanned entries at or below one of `subpaths`, relative to the root.
+    ///
+    /// This applies to the current result, so call it after scanning. The root itself is
+    /// dropped unless one of `subpaths` is empty.
+    fn restrict_to(&mut self, subpaths: &[&Path]);
+    /// Skip entries matching any of the glob `patterns`, pruning excluded directories.
+    ///
+    /// A pattern without `/` matches file names, any other matches the whole path.
+    /// `*` and `?` never cross a `/`, while `**` does. The root is never excluded.
+    fn exclude(&mut self, patterns: &[String]) {
+        self.options_mut().excludes.extend_from_slice(patterns);
+    }
+    /// Skip entries whose path matches any of the regular expressions, pruning excluded
+    /// directories like [`exclude`](Self::exclude).
+    ///
+    /// Each pattern is searched in the whole path as scanned, so anchor it with `^`, `$`
+    /// or `/` to match components. An entry is skipped when either a glob or a regex
+    /// matches, neither takes precedence, and the root is never excluded.
+    #[cfg(feature = "regex")]
+    fn exclude_regex(&mut self, patterns: &[Regex]) {
+        self.options_mut()
+            .regex_excludes
+            .extend_from_slice(patterns);
+    }
+    /// Whether a scan would record `path`, checking the configured rules without scanning.
+    ///
+    /// Paths outside the root are never included.
+    fn would_include(&self, path: &Path) -> bool;
+    /// Record the canonical absolute path of each entry in `abs_path`, off by default.
+    ///
+    /// Retained symlinks keep their own name, and entries which cannot be canonicalized
+    /// are left `None`.
+    fn record_abs_path(&mut self, enable: bool) {
+        self.options_mut().abs_path = enable;
+    }
+    /// Record the mount point holding each entry in `mount_point`, off by default.
+    ///
+    /// Mounts are read from `/proc/self/mountinfo` when a scan starts and entries are
+    /// matched by their canonical path, so other systems leave `mount_point` `None`.
+    fn record_mount_point(&mut self, enable: bool) {
+        self.options_mut().mount_point = enable;
+    }
+    /// Give up a scan once it has run for `timeout`, keeping the entries found so far.
+    ///
+    /// The deadline is checked between entries, so a single slow entry can overrun it.
+    fn timeout(&mut self, timeout: Duration) {
+        self.options_mut().timeout = Some(timeout);
+    }
+    /// Whether the last scan stopped at the timeout with partial results, never for
+    /// filters which do not honour one.
+    fn timed_out(&self) -> bool {
+        false
+    }
+    /// Hold a permit of `limiter` while each directory is open for listing.
+    fn limit_open_files(&mut self, limiter: Arc<Limiter>) {
+        self.options_mut().limiter = Some(limiter);
+    }
+    /// Check that the root can be scanned, telling why not instead of a scan leaving the
+    /// result `None`.
+    fn validate(&self) -> Result<(), RootError>;
+
+    /// Like [`scan`](Self::scan), visiting the entries of each directory level on up to
+    /// `threads` threads. The results come in the same order as a sequential scan.
+    ///
+    /// Filters which cannot split their scan run it on the calling thread.
+    fn scan_parallel(&mut self, _threads: usize) {
+        self.scan();
+    }
+
+    /// Like [`scan`](Self::scan), handing each entry to `cb` as soon as it is found
+    /// instead of collecting them, so memory does not grow with the tree. `files` is
+    /// `None` afterwards, and nothing is handed on when the root does not exist.
+    ///
+    /// Filters which cannot hand entries on while scanning collect them first, and keep
+    /// them in `files`.
+    fn scan_each(&mut self, cb: &mut dyn FnMut(FileInfo)) {
+        self.scan();
+        self.files().iter().flatten().cloned().for_each(cb);
+    }
+
+    /// Like [`update`](Self::update), but when `root` lies inside the scanned tree the
+    /// entries under it are kept as the result, so no new scan is needed.
+    ///
+    /// The kept entries reflect the filesystem at the time of the earlier scan. Other
+    /// roots, scans which timed out and scans which followed a directory symlink, whose
+    /// contents are recorded under the target's path, leave the result empty as `update`
+    /// does. Filters without a cache to keep are simply updated.
+    fn update_keeping_cache(self, root: &Path) -> Self
+    where
+        Self: Sized,
+    {
+        self.update(root)
+    }
+
+    /// Number of entries found by the last scan, 0 before scanning.
+    fn len(&self) -> usize {
+        self.files().as_ref().map_or(0, |files| files.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counts and total size of the entries found by the last scan, `None` before scanning.
+    fn summary(&self) -> Option<ScanStats> {
+        let files = self.files().as_ref()?;
+        let mut stats = ScanStats::default();
+        files
+            .iter()
+            .for_each(|f| match (&f.symlink_path, f.file_type) {
+                (Some(_), _) | (None, FileType::SYMLINK) => stats.symlinks += 1,
+                (None, FileType::DIRECTORY) => stats.dirs += 1,
+                (None, FileType::REGULAR) => {
+                    stats.files += 1;
+                    stats.total_size += f.size;
+                }
+                (None, FileType::NONE) => stats.other += 1,
+            });
+        Some(stats)
+    }
+
+    /// Directories found by the last scan, to create the tree before its files.
+    ///
+    /// Followed directory symlinks are included, retained ones are not.
+    fn dirs_only(&self) -> Vec<FileInfo> {
+        self.files()
+            .iter()
+            .flatten()
+            .filter(|f| f.file_type == FileType::DIRECTORY && f.symlink_path.is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Build a tree from the scan result, rooted at the scanned path.
+    fn to_tree(&self) -> DirNode {
+        let mut tree = DirNode::default();
+        if let Some(files) = self.files() {
+            if let Some(root) = files.first() {
+                let root = Path::new(&root.path);
+                files.iter().for_each(|f| {
+                    let path = Path::new(&f.path);
+                    tree.insert(path.strip_prefix(root).unwrap_or(path), f.clone())
+                });
+            }
+        }
+        tree
+    }
 }
 
 /// The filter consider all of the files into regular files ignoring symlinks, and
@@ -57,6 +494,8 @@ pub trait Filter: IntoIterator<Item = FileInfo> {
 pub struct BasicFilter {
     root: String,
     files: Option<Vec<FileInfo>>,
+    options: ScanOptions,
+    timed_out: bool,
 }
 
 /// The filter does not follow the symlink, even if the symlink
@@ -85,6 +524,8 @@ pub struct BasicFilter {
 pub struct SymlinkFilter {
     root: String,
     files: Option<Vec<FileInfo>>,
+    options: ScanOptions,
+    timed_out: bool,
 }
 
 /// The filter follows the symlink, and transfer all symlink to copy of what it points to.
@@ -101,387 +542,2423 @@ pub struct SymlinkFilter {
 pub struct SymlinkFollowFilter {
     root: String,
     files: Option<Vec<FileInfo>>,
+    options: ScanOptions,
+    timed_out: bool,
 }
 
-impl BasicFilter {
-    fn list_files(&self) -> Option<Vec<FileInfo>> {
-        let root = self.root.clone();
-        if Path::new(&root).is_symlink() {
-            return Some(vec![FileInfo::new(
-                Path::new(&root),
-                Path::new(&root),
-                FileType::REGULAR,
-                None,
-            )]);
-        }
-        if !Path::new(&self.root).try_exists().is_ok_and(|x| x) {
-            return None;
-        }
-        let mut results = Vec::new();
-        let mut queue = VecDeque::new();
-        queue.push_back(root);
-        while !queue.is_empty() {
-            let root = queue.pop_front().unwrap();
-            let root_path = Path::new(&root);
-            results.push(FileInfo::new(
-                root_path,
-                root_path,
-                if root_path.is_symlink() {
-                    FileType::REGULAR
-                } else if root_path.is_dir() {
-                    FileType::DIRECTORY
-                } else {
-                    FileType::REGULAR
-                },
-                None,
-            ));
-            if root_path.is_file() || root_path.is_symlink() {
-                continue;
-            }
-            for subfile in root_path.read_dir().unwrap() {
-                queue.push_back(subfile.unwrap().path().to_str().unwrap().to_owned());
+/// How a scan treats the symlinks it meets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Record symlinks as regular files, behavior of [`BasicFilter`].
+    Ignore,
+    /// Record symlinks with their targets but never descend, behavior of [`SymlinkFilter`].
+    Retain,
+    /// Replace symlinks with what they point to, behavior of [`SymlinkFollowFilter`].
+    Follow,
+    /// Leave symlinks out, behavior of [`SymlinkFilter::skip_symlinks`].
+    Skip,
+}
+
+/// Links followed before a chain is considered a loop, as `ELOOP` on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Where a chain of symlinks ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymlinkResolution {
+    // last path reached, relative targets joined to the directory of their link while
+    // absolute ones replace the path so far
+    pub target: PathBuf,
+    // whether the target exists, false for dangling links and loops
+    pub exists: bool,
+    // whether the chain does not reach a non-symlink within the hop limit
+    pub looped: bool,
+}
+
+/// Follow `path` through every symlink in the chain, as [`SymlinkFollowFilter`] does.
+///
+/// A path which is not a symlink resolves to itself.
+pub fn resolve_symlink(path: &Path) -> SymlinkResolution {
+    resolve_symlink_hops(path, MAX_SYMLINK_HOPS)
+}
+
+/// `resolve_symlink` reading at most `max_hops` links, longer chains count as loops.
+fn resolve_symlink_hops(path: &Path, max_hops: usize) -> SymlinkResolution {
+    let mut target = path.to_path_buf();
+    let mut hops = 0;
+    loop {
+        let points_to = match fs::read_link(&target) {
+            Ok(points_to) => points_to,
+            Err(_) => {
+                return SymlinkResolution {
+                    exists: target.try_exists().is_ok_and(|x| x),
+                    target,
+                    looped: false,
+                }
             }
+        };
+        if hops == max_hops {
+            return SymlinkResolution {
+                target,
+                exists: false,
+                looped: true,
+            };
         }
-        Some(results)
+        target = target.parent().unwrap_or(Path::new("")).join(points_to);
+        hops += 1;
     }
 }
 
-impl Filter for BasicFilter {
-    fn new(root: &Path) -> Self {
-        Self {
-            root: root.to_str().expect("not valid UTF-8 path").to_owned(),
-            files: None,
+/// Why a root cannot be scanned, see [`validate_root`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootError {
+    NotFound,
+    NotUtf8,
+    PermissionDenied,
+    // any other failure to read the root
+    Other(io::ErrorKind),
+}
+
+impl fmt::Display for RootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootError::NotFound => write!(f, "root does not exist"),
+            RootError::NotUtf8 => write!(f, "root is not valid UTF-8"),
+            RootError::PermissionDenied => write!(f, "root cannot be read, permission denied"),
+            RootError::Other(kind) => write!(f, "root cannot be read, {}", kind),
         }
     }
+}
 
-    fn scan(&mut self) {
-        self.files = self.list_files();
+impl From<io::Error> for RootError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => RootError::NotFound,
+            io::ErrorKind::PermissionDenied => RootError::PermissionDenied,
+            kind => RootError::Other(kind),
+        }
     }
+}
 
-    fn files(&self) -> &Option<Vec<FileInfo>> {
-        &self.files
+/// Check that `root` names an entry a filter can scan, and a directory it can list.
+///
+/// Filters store roots as UTF-8, so check paths from outside before constructing one.
+pub fn validate_root(root: &Path) -> Result<(), RootError> {
+    root.to_str().ok_or(RootError::NotUtf8)?;
+    if fs::symlink_metadata(root)?.is_dir() {
+        fs::read_dir(root)?;
     }
+    Ok(())
+}
 
-    fn update(self, root: &Path) -> Self {
-        let mut instance = self;
-        instance.root = root.to_str().expect("invalid path").to_owned();
-        instance.files = None;
-        instance
+/// Expand a leading `~` and the `$NAME` or `${NAME}` environment variables in `path`, as
+/// a shell would for a root typed as `~/docs` or `$HOME/docs`.
+///
+/// Filters take roots literally, so expansion is up to the caller. Variables which are
+/// unset or not valid UTF-8 are left as written, as is `~user`.
+pub fn expand_path(path: &str) -> String {
+    let var = |name: &str| env::var(name).ok();
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        if let Some(home) = var("HOME") {
+            expanded.push_str(&home);
+            rest = &rest[1..];
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match (name.is_empty(), var(name)) {
+            (false, Some(value)) => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
     }
+    expanded.push_str(rest);
+    expanded
 }
 
-impl IntoIterator for BasicFilter {
-    type Item = FileInfo;
-    type IntoIter = IntoIter<Self::Item>;
+/// Mount points listed in `mountinfo`, in the format of `/proc/self/mountinfo`.
+fn parse_mountinfo(mountinfo: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount)
+        .collect()
+}
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.files.unwrap_or(Vec::new()).into_iter()
+/// `field` with the octal escapes of mountinfo, `\040` for a space, decoded.
+fn unescape_mount(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = |digits: &&[u8]| digits.iter().all(|d| (b'0'..=b'7').contains(d));
+        let escape = match bytes[i] {
+            b'\\' => bytes.get(i + 1..i + 4).filter(octal),
+            _ => None,
+        };
+        match escape {
+            Some(digits) => {
+                let byte = digits
+                    .iter()
+                    .fold(0u8, |n, d| n.wrapping_mul(8).wrapping_add(d - b'0'));
+                decoded.push(byte);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
     }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
-impl SymlinkFilter {
-    fn list_files(&self) -> Option<Vec<FileInfo>> {
-        let root = self.root.clone();
-        if Path::new(&self.root).is_symlink() {
-            return Some(vec![Self::query_fileinfo(&self.root)]);
-        }
-        if !Path::new(&self.root).try_exists().is_ok_and(|x| x) {
-            return None;
-        }
-        let mut results = Vec::new();
-        let mut queue = VecDeque::new();
-        queue.push_back(root);
-        while !queue.is_empty() {
-            let next = queue.pop_front().expect("unreachable");
-            results.push(Self::query_fileinfo(&next));
-            match Self::query_next_batch(&next) {
-                Some(next) => next.into_iter().for_each(|p| queue.push_back(p)),
-                None => {}
-            };
+/// The deepest of `mounts` containing `path`.
+fn mount_point_of(path: &Path, mounts: &[String]) -> Option<String> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(mount))
+        .max_by_key(|mount| Path::new(mount).components().count())
+        .cloned()
+}
+
+type FollowPredicate = Arc<dyn Fn(&FileInfo) -> bool + Send + Sync>;
+
+/// Entries of a scan by type, see [`Filter::summary`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    // special files and dangling or looping followed symlinks
+    pub other: usize,
+    // recorded sizes of the regular files
+    pub total_size: u64,
+}
+
+/// Settings of a filter applied while walking, see [`Filter::options_mut`].
+///
+/// The fields are private: a filter holds the default and leaves its changes to the
+/// provided setters of [`Filter`].
+#[derive(Clone, Default)]
+pub struct ScanOptions {
+    excludes: Vec<String>,
+    #[cfg(feature = "regex")]
+    regex_excludes: Vec<Regex>,
+    follow_if: Option<FollowPredicate>,
+    abs_path: bool,
+    mount_point: bool,
+    timeout: Option<Duration>,
+    retain_dir_links: bool,
+    keep_dir_links: bool,
+    max_symlink_hops: Option<usize>,
+    limiter: Option<Arc<Limiter>>,
+    skip_symlinks: bool,
+    within_root: bool,
+    resolve_root: bool,
+    // canonical root of the scan when symlinks are only followed within it
+    root_bound: Option<PathBuf>,
+}
+
+impl ScanOptions {
+    fn is_excluded(&self, path: &str) -> bool {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(path);
+        #[cfg(feature = "regex")]
+        if self.regex_excludes.iter().any(|r| r.is_match(path)) {
+            return true;
         }
-        Some(results)
+        self.excludes.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern, path)
+            } else {
+                glob_match(pattern, name)
+            }
+        })
     }
 
-    //assume path exists
-    fn query_fileinfo(path: &str) -> FileInfo {
-        let abstract_path = Path::new(path);
-        if abstract_path.is_symlink() {
-            match fs::read_link(abstract_path) {
-                Ok(points_to) => FileInfo::new(
-                    Path::new(path),
-                    Path::new(path),
-                    if !points_to.try_exists().is_ok_and(|x| x) {
-                        FileType::NONE
-                    } else if points_to.is_symlink() {
-                        FileType::SYMLINK
-                    } else if points_to.is_dir() {
-                        FileType::DIRECTORY
-                    } else {
-                        FileType::REGULAR
-                    },
-                    Some(points_to.as_path()),
-                ),
-                Err(_) => unreachable!(),
-            }
-        } else {
-            FileInfo::new(
-                abstract_path,
-                abstract_path,
-                if abstract_path.is_dir() {
-                    FileType::DIRECTORY
-                } else {
-                    FileType::REGULAR
-                },
-                None,
-            )
+    /// Whether `path` and every directory between it and `root` pass the rules.
+    fn would_include(&self, root: &str, path: &Path) -> bool {
+        let mut current = Path::new(root).to_path_buf();
+        match path.strip_prefix(root) {
+            Ok(relative) => relative.components().all(|c| {
+                current.push(c);
+                !self.is_excluded(current.to_str().expect("invalid path"))
+            }),
+            Err(_) => false,
         }
     }
-    fn query_next_batch(path: &str) -> Option<Vec<String>> {
-        let abstract_path = Path::new(path);
-        if abstract_path.is_symlink() {
-            None
-        } else if abstract_path.is_file() {
-            None
-        } else {
-            Some(Vec::from_iter(
-                abstract_path
-                    .read_dir()
-                    .expect("invalid path")
-                    .filter(|e| e.is_ok())
-                    .map(|e| e.unwrap().path().to_str().expect("invalid path").to_owned()),
-            ))
+}
+
+/// Entries of a finished scan at or below `root`, `None` if `root` was not scanned or
+/// the entries cannot be told apart by path.
+fn cached_subtree(files: &Option<Vec<FileInfo>>, root: &Path) -> Option<Vec<FileInfo>> {
+    let files = files.as_ref()?;
+    let followed_dir =
+        |f: &FileInfo| f.file_type == FileType::DIRECTORY && f.path != f.content_path;
+    if !files.iter().any(|f| Path::new(&f.path) == root) || files.iter().any(followed_dir) {
+        return None;
+    }
+    Some(
+        files
+            .iter()
+            .filter(|f| Path::new(&f.path).starts_with(root))
+            .cloned()
+            .collect(),
+    )
+}
+
+fn restrict(files: &mut Option<Vec<FileInfo>>, root: &str, subpaths: &[&Path]) {
+    let subpaths: Vec<_> = subpaths.iter().map(|p| Path::new(root).join(p)).collect();
+    if let Some(files) = files {
+        files.retain(|f| subpaths.iter().any(|p| Path::new(&f.path).starts_with(p)));
+    }
+}
+
+/// Match `text` against a glob, `*` and `?` stay within a component while `**` does not.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(p: &[char], t: &[char]) -> bool {
+        match p.first() {
+            None => t.is_empty(),
+            Some('*') if p.get(1) == Some(&'*') => (0..=t.len()).any(|i| matches(&p[2..], &t[i..])),
+            Some('*') => (0..=t.len())
+                .take_while(|&i| i == 0 || t[i - 1] != '/')
+                .any(|i| matches(&p[1..], &t[i..])),
+            Some('?') => t.first().is_some_and(|c| *c != '/') && matches(&p[1..], &t[1..]),
+            Some(c) => t.first() == Some(c) && matches(&p[1..], &t[1..]),
         }
     }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
 }
 
-impl Filter for SymlinkFilter {
-    fn new(root: &Path) -> Self {
+/// Breadth first walk yielding the entries of a directory as they are found, the traversal
+/// behind the filters without collecting its results.
+///
+/// ```
+/// # use clannad::filter::{DirWalk, SymlinkPolicy};
+/// # use std::path::Path;
+/// let walk = DirWalk::new(Path::new("resources/normalfolder"), SymlinkPolicy::Retain)
+///     .with_max_depth(1);
+/// assert_eq!(walk.count(), 6);
+/// ```
+pub struct DirWalk {
+    walker: Walker,
+    max_depth: usize,
+    // paths still to visit with their depth below the root
+    queue: VecDeque<(String, usize)>,
+    // canonical path of each directory entered by path
+    visited: HashMap<String, PathBuf>,
+}
+
+impl DirWalk {
+    /// Walk of `root` treating symlinks by `policy`, empty if `root` does not exist.
+    pub fn new(root: &Path, policy: SymlinkPolicy) -> Self {
+        let options = ScanOptions::default();
+        Self::scan(&normalize(root), policy, &options)
+            .unwrap_or_else(|| Self::resume(VecDeque::new(), policy, &options))
+    }
+
+    /// Yield entries at most `depth` levels below the root, which is level 0.
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Skip the entries matching the glob `patterns`, see [`Filter::exclude`].
+    pub fn with_excludes(mut self, patterns: &[String]) -> Self {
+        self.walker.options.excludes.extend_from_slice(patterns);
+        self
+    }
+
+    /// Walk of `root` with the settings of a filter, `None` if the root does not exist.
+    fn scan(root: &str, policy: SymlinkPolicy, options: &ScanOptions) -> Option<Self> {
+        let mut walk = Self::resume(VecDeque::new(), policy, options);
+        let queue = walk.walker.start(root)?.queue;
+        walk.queue = queue.into_iter().map(|path| (path, 0)).collect();
+        Some(walk)
+    }
+
+    /// Walk continuing with the queued `paths` of an interrupted one.
+    fn resume(paths: VecDeque<String>, policy: SymlinkPolicy, options: &ScanOptions) -> Self {
         Self {
-            root: root.to_str().expect("invalid path").to_owned(),
-            files: None,
+            walker: Walker::new(policy, options),
+            max_depth: usize::MAX,
+            queue: paths.into_iter().map(|path| (path, 0)).collect(),
+            visited: HashMap::new(),
         }
     }
-    fn scan(&mut self) {
-        self.files = self.list_files();
-    }
-    fn files(&self) -> &Option<Vec<FileInfo>> {
-        &self.files
+
+    /// Whether the walk stopped at the deadline of the filter settings.
+    fn timed_out(&self) -> bool {
+        self.walker.timed_out.load(Ordering::Relaxed)
     }
-    fn update(self, root: &Path) -> Self {
-        let mut instance = self;
-        instance.root = root.to_str().expect("invalid path").to_owned();
-        instance.files = None;
-        instance
+
+    /// Paths still to visit, to resume the walk from.
+    fn into_queue(self) -> VecDeque<String> {
+        self.queue.into_iter().map(|(path, _)| path).collect()
     }
 }
 
-impl IntoIterator for SymlinkFilter {
+impl Iterator for DirWalk {
     type Item = FileInfo;
-    type IntoIter = IntoIter<FileInfo>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.files.unwrap_or(Vec::new()).into_iter()
+    /// Skips queued paths which no longer exist.
+    fn next(&mut self) -> Option<FileInfo> {
+        while !self.queue.is_empty() {
+            if self.walker.past_deadline() {
+                self.walker.timed_out.store(true, Ordering::Relaxed);
+                return None;
+            }
+            let (path, depth) = self.queue.pop_front()?;
+            if let Some((info, children)) = self.walker.visit(&path, &mut self.visited) {
+                if depth < self.max_depth {
+                    self.queue
+                        .extend(children.into_iter().map(|child| (child, depth + 1)));
+                }
+                return Some(info);
+            }
+        }
+        None
     }
 }
 
-impl Filter for SymlinkFollowFilter {
-    fn new(root: &Path) -> Self {
+/// Entry read by [`Walker::prepare`], waiting for [`Walker::settle`] to decide which
+/// directories to enter.
+struct Visit {
+    info: FileInfo,
+    // canonical path of a directory reached while following symlinks
+    canonical: Option<PathBuf>,
+    // target of a kept directory symlink with its canonical path
+    linked: Option<(String, PathBuf)>,
+    children: Vec<String>,
+}
+
+/// Visits of single entries with the settings of a filter, shared by [`DirWalk`] and the
+/// parallel scan.
+struct Walker {
+    policy: SymlinkPolicy,
+    options: ScanOptions,
+    deadline: Option<Instant>,
+    // whether a walk stopped at the deadline
+    timed_out: AtomicBool,
+    // mount points when recording them
+    mounts: Vec<String>,
+}
+
+impl Walker {
+    fn new(policy: SymlinkPolicy, options: &ScanOptions) -> Self {
         Self {
-            root: root.to_str().expect("invalid path").to_owned(),
-            files: None,
+            policy,
+            options: options.clone(),
+            deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+            timed_out: AtomicBool::new(false),
+            mounts: match options.mount_point {
+                true => fs::read_to_string("/proc/self/mountinfo")
+                    .map_or(Vec::new(), |mountinfo| parse_mountinfo(&mountinfo)),
+                false => Vec::new(),
+            },
         }
     }
 
-    fn scan(&mut self) {
-        self.files = self.list_files();
+    /// `list_files` visiting each level of the tree on up to `threads` threads.
+    ///
+    /// Every level is split into consecutive chunks which are read on separate threads,
+    /// then the entries are settled one by one in discovery order, so the directories
+    /// entered and the output match the sequential walk.
+    fn list_files_parallel(&self, root: &str, threads: usize) -> Option<Vec<FileInfo>> {
+        let mut level = Vec::from(self.start(root)?.queue);
+        let mut results = Vec::new();
+        // canonical path of each directory entered by path
+        let mut visited = HashMap::new();
+        while !level.is_empty() {
+            if self.past_deadline() {
+                self.timed_out.store(true, Ordering::Relaxed);
+                break;
+            }
+            let chunk_size = level.len().div_ceil(threads.max(1));
+            let chunks: Vec<Vec<_>> = thread::scope(|scope| {
+                let handles: Vec<_> = level
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || chunk.iter().map(|path| self.prepare(path)).collect())
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("scan thread panicked"))
+                    .collect()
+            });
+            let mut next = Vec::new();
+            for visit in chunks.into_iter().flatten().flatten() {
+                let (info, children) = self.settle(visit, &mut visited);
+                results.push(info);
+                next.extend(children);
+            }
+            level = next;
+        }
+        Some(results)
     }
 
-    fn files(&self) -> &Option<Vec<FileInfo>> {
-        &self.files
+    /// Checkpoint queued with `root` only, `None` if the root does not exist.
+    fn start(&self, root: &str) -> Option<ScanCheckpoint> {
+        let root_path = Path::new(root);
+        let exists = match self.policy {
+            SymlinkPolicy::Follow => root_path.try_exists().is_ok_and(|x| x),
+            _ => root_path.is_symlink() || root_path.try_exists().is_ok_and(|x| x),
+        };
+        if !exists {
+            return None;
+        }
+        let mut checkpoint = ScanCheckpoint::default();
+        checkpoint.queue.push_back(root.to_owned());
+        Some(checkpoint)
     }
 
-    fn update(self, root: &Path) -> Self {
-        let mut instance = self;
-        instance.root = root.to_str().expect("invalid path").to_owned();
-        instance.files = None;
-        instance
+    /// Whether the timeout of the settings has run out.
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
     }
-}
 
-impl SymlinkFollowFilter {
-    fn list_files(&self) -> Option<Vec<FileInfo>> {
-        let root = self.root.clone();
-        if !Path::new(&root).try_exists().is_ok_and(|x| x) {
+    /// Entry at `path` and the paths to queue after it, `None` if it is gone or skipped.
+    ///
+    /// `visited` maps the directories entered so far to their canonical path.
+    fn visit(
+        &self,
+        path: &str,
+        visited: &mut HashMap<String, PathBuf>,
+    ) -> Option<(FileInfo, Vec<String>)> {
+        self.prepare(path).map(|visit| self.settle(visit, visited))
+    }
+
+    /// Read the entry at `path` without deciding what to enter, `None` if it is gone or
+    /// skipped. Only reads the filesystem, so entries can be prepared in any order.
+    fn prepare(&self, path: &str) -> Option<Visit> {
+        let metadata = lstat(Path::new(path)).ok()?;
+        if metadata.file_type().is_symlink() && self.policy == SymlinkPolicy::Skip {
             return None;
         }
-        let mut queue = VecDeque::new();
-        let mut results = Vec::new();
-        queue.push_back(root);
-
-        while !queue.is_empty() {
-            let root = queue.pop_front().unwrap();
-            results.push(Self::query_fileinfo(&root));
-            Self::query_next_batch(&root)
-                .iter()
-                .for_each(|p| queue.push_back(p.to_owned()));
+        let mut info = self.query_fileinfo(path, &metadata);
+        if self.options.abs_path {
+            info.abs_path = Self::canonical_path(&info);
         }
-        Some(results)
+        if self.options.mount_point {
+            info.mount_point = Self::canonical_path(&info)
+                .and_then(|canonical| mount_point_of(Path::new(&canonical), &self.mounts));
+        }
+        let canonical =
+            match self.policy == SymlinkPolicy::Follow && info.file_type == FileType::DIRECTORY {
+                true => fs::canonicalize(&info.content_path).ok(),
+                false => None,
+            };
+        let linked = self.linked_dir(&info);
+        let children = self
+            .query_next_batch(&info)
+            .into_iter()
+            .filter(|p| !self.options.is_excluded(p))
+            .collect();
+        Some(Visit {
+            info,
+            canonical,
+            linked,
+            children,
+        })
     }
 
-    fn query_next_batch(path: &str) -> Vec<String> {
-        let info = Self::query_fileinfo(path);
-        let abstract_path = Path::new(&info.content_path);
-        if abstract_path.is_file() {
-            vec![]
-        } else {
-            abstract_path
-                .read_dir()
-                .unwrap()
-                .map(|x| x.unwrap())
-                .map(|x| x.path())
-                .map(|p| p.to_str().unwrap().to_owned())
-                .collect()
-        }
+    /// The entry of `visit` and the paths to queue after it, in the order of the walk.
+    ///
+    /// When following symlinks, a directory which is one of its own ancestors is recorded
+    /// but not entered again, so links to an ancestor cannot loop forever.
+    fn settle(
+        &self,
+        visit: Visit,
+        visited: &mut HashMap<String, PathBuf>,
+    ) -> (FileInfo, Vec<String>) {
+        let Visit {
+            info,
+            canonical,
+            linked,
+            children,
+        } = visit;
+        let entered = match canonical {
+            Some(canonical) => {
+                let looped = Path::new(&info.path)
+                    .ancestors()
+                    .skip(1)
+                    .filter_map(|a| a.to_str().and_then(|a| visited.get(a)))
+                    .any(|a| *a == canonical);
+                visited.insert(info.path.clone(), canonical);
+                !looped
+            }
+            None => true,
+        };
+        let linked = linked.filter(|(_, canonical)| !visited.values().any(|c| c == canonical));
+        let children = match (entered, linked) {
+            (_, Some((target, canonical))) => {
+                visited.insert(target.clone(), canonical);
+                vec![target]
+            }
+            (true, None) => children,
+            (false, None) => vec![],
+        };
+        (info, children)
     }
-    fn query_fileinfo(path: &str) -> FileInfo {
-        let abstract_path = Path::new(path);
-        if abstract_path.is_symlink() {
-            Self::follow_link(path)
-        } else {
-            FileInfo::new(
+
+    /// Target of a directory symlink kept by `keep_dir_links` with its canonical path, to
+    /// traverse under its own path unless a directory with the same canonical path was
+    /// entered or queued before.
+    fn linked_dir(&self, info: &FileInfo) -> Option<(String, PathBuf)> {
+        if self.policy != SymlinkPolicy::Follow
+            || !self.options.keep_dir_links
+            || info.symlink_path.is_none()
+        {
+            return None;
+        }
+        let followed = self.follow_link(&info.path);
+        if followed.file_type != FileType::DIRECTORY
+            || self.escapes_root(&followed)
+            || self
+                .options
+                .follow_if
+                .as_ref()
+                .is_some_and(|f| !f(&followed))
+            || self.options.is_excluded(&followed.content_path)
+        {
+            return None;
+        }
+        let canonical = fs::canonicalize(&followed.content_path).ok()?;
+        Some((followed.content_path, canonical))
+    }
+
+    /// Whether `followed` resolves outside the root it must stay within, comparing
+    /// canonical paths so neither `..` nor a sibling sharing the root's prefix passes.
+    /// Targets which cannot be canonicalized count as outside.
+    fn escapes_root(&self, followed: &FileInfo) -> bool {
+        let Some(bound) = &self.options.root_bound else {
+            return false;
+        };
+        !fs::canonicalize(&followed.content_path).is_ok_and(|target| target.starts_with(bound))
+    }
+
+    /// Entry for `path`, whose `metadata` was read without following it.
+    fn query_fileinfo(&self, path: &str, metadata: &Metadata) -> FileInfo {
+        let abstract_path = Path::new(path);
+        let file_type = FileType::from_metadata(metadata, false);
+        if file_type != FileType::SYMLINK {
+            return FileInfo::with_metadata(
                 abstract_path,
                 abstract_path,
-                if abstract_path.is_dir() {
-                    FileType::DIRECTORY
-                } else {
-                    FileType::REGULAR
-                },
+                file_type,
                 None,
-            )
+                Some(metadata),
+            );
+        }
+        match self.policy {
+            SymlinkPolicy::Ignore => {
+                FileInfo::new(abstract_path, abstract_path, FileType::REGULAR, None)
+            }
+            SymlinkPolicy::Retain | SymlinkPolicy::Skip => Self::retain_link(path, metadata),
+            SymlinkPolicy::Follow => {
+                let followed = self.follow_link(path);
+                let retained = ((self.options.retain_dir_links || self.options.keep_dir_links)
+                    && followed.file_type == FileType::DIRECTORY)
+                    || self.escapes_root(&followed)
+                    || self
+                        .options
+                        .follow_if
+                        .as_ref()
+                        .is_some_and(|f| !f(&followed));
+                match retained {
+                    true => Self::retain_link(path, metadata),
+                    false => followed,
+                }
+            }
+        }
+    }
+
+    /// Children of a directory entry; symlinks are only entered once followed.
+    fn query_next_batch(&self, info: &FileInfo) -> Vec<String> {
+        if info.symlink_path.is_some() || info.file_type != FileType::DIRECTORY {
+            return vec![];
+        }
+        let _permit = self.options.limiter.as_ref().map(|l| l.acquire());
+        match Path::new(&info.content_path).read_dir() {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path().to_str().expect("invalid path").to_owned())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn canonical_path(info: &FileInfo) -> Option<String> {
+        let path = Path::new(&info.content_path);
+        let canonical = match (&info.symlink_path, path.file_name()) {
+            (Some(_), Some(name)) => {
+                fs::canonicalize(path.parent().unwrap_or(Path::new("."))).map(|p| p.join(name))
+            }
+            _ => fs::canonicalize(path),
+        };
+        canonical
+            .ok()
+            .and_then(|p| p.to_str().map(|p| p.to_owned()))
+    }
+
+    fn retain_link(path: &str, metadata: &Metadata) -> FileInfo {
+        match fs::read_link(path) {
+            Ok(points_to) => FileInfo::with_metadata(
+                Path::new(path),
+                Path::new(path),
+                FileType::from_path(&points_to),
+                Some(points_to.as_path()),
+                Some(metadata),
+            ),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    fn follow_link(&self, symlink: &str) -> FileInfo {
+        let max_hops = self.options.max_symlink_hops.unwrap_or(MAX_SYMLINK_HOPS);
+        let resolution = resolve_symlink_hops(Path::new(symlink), max_hops);
+        let metadata = match resolution.looped {
+            true => None,
+            false => stat(&resolution.target).ok(),
+        };
+        let file_type = metadata
+            .as_ref()
+            .map_or(FileType::NONE, |m| FileType::from_metadata(m, false));
+        FileInfo::with_metadata(
+            Path::new(symlink),
+            &resolution.target,
+            file_type,
+            None,
+            metadata.as_ref(),
+        )
+    }
+}
+
+impl Filter for BasicFilter {
+    fn new(root: &Path) -> Self {
+        Self {
+            root: normalize(root),
+            files: None,
+            options: ScanOptions::default(),
+            timed_out: false,
+        }
+    }
+
+    fn scan(&mut self) {
+        let mut walk = DirWalk::scan(&self.root, SymlinkPolicy::Ignore, &self.options);
+        self.files = walk.as_mut().map(|walk| walk.collect());
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+    }
+
+    fn scan_parallel(&mut self, threads: usize) {
+        let walker = Walker::new(SymlinkPolicy::Ignore, &self.options);
+        self.files = walker.list_files_parallel(&self.root, threads);
+        self.timed_out = walker.timed_out.load(Ordering::Relaxed);
+    }
+
+    fn scan_each(&mut self, cb: &mut dyn FnMut(FileInfo)) {
+        let mut walk = DirWalk::scan(&self.root, SymlinkPolicy::Ignore, &self.options);
+        self.files = None;
+        if let Some(walk) = walk.as_mut() {
+            walk.for_each(cb);
+        }
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+    }
+
+    fn files(&self) -> &Option<Vec<FileInfo>> {
+        &self.files
+    }
+
+    fn update(self, root: &Path) -> Self {
+        let mut instance = self;
+        instance.root = normalize(root);
+        instance.files = None;
+        instance.timed_out = false;
+        instance
+    }
+
+    fn update_keeping_cache(self, root: &Path) -> Self {
+        let cached = match self.timed_out {
+            true => None,
+            false => cached_subtree(&self.files, root),
+        };
+        let mut instance = self.update(root);
+        instance.files = cached;
+        instance
+    }
+
+    fn restrict_to(&mut self, subpaths: &[&Path]) {
+        restrict(&mut self.files, &self.root, subpaths);
+    }
+
+    fn options_mut(&mut self) -> &mut ScanOptions {
+        &mut self.options
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    fn validate(&self) -> Result<(), RootError> {
+        validate_root(Path::new(&self.root))
+    }
+
+    fn would_include(&self, path: &Path) -> bool {
+        self.options.would_include(&self.root, path)
+    }
+}
+
+impl IntoIterator for BasicFilter {
+    type Item = FileInfo;
+    type IntoIter = IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.unwrap_or(Vec::new()).into_iter()
+    }
+}
+
+impl SymlinkFilter {
+    /// Leave symlinks out of the results altogether instead of recording them, so only
+    /// real files and directories remain.
+    pub fn skip_symlinks(&mut self, skip: bool) {
+        self.options.skip_symlinks = skip;
+    }
+
+    fn policy(&self) -> SymlinkPolicy {
+        match self.options.skip_symlinks {
+            true => SymlinkPolicy::Skip,
+            false => SymlinkPolicy::Retain,
+        }
+    }
+
+    /// When the root is a symlink to a directory, scan that directory instead of
+    /// recording the link alone. Symlinks inside are still retained or skipped as usual.
+    /// Off by default.
+    ///
+    /// Entries keep the root's own name in `path`, such as `link/file` for a root `link`
+    /// pointing to `dir/target`, while `content_path` holds the resolved `dir/target/file`.
+    ///
+    /// ```
+    /// # use clannad::filter::{Filter, SymlinkFilter};
+    /// # use std::path::Path;
+    /// let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder/current"));
+    /// filter.resolve_root(true);
+    /// filter.scan();
+    /// let files: Vec<_> = filter.into_iter().collect();
+    /// assert_eq!(files.len(), 3);
+    /// assert_eq!(files[0].path, "resources/normalfolder/current");
+    /// assert_eq!(files[0].content_path, "resources/normalfolder/level1");
+    /// ```
+    pub fn resolve_root(&mut self, resolve: bool) {
+        self.options.resolve_root = resolve;
+    }
+
+    /// Root handed to the walker, the target of a symlink root with `resolve_root`.
+    fn scan_root(&self) -> String {
+        if !self.options.resolve_root {
+            return self.root.clone();
+        }
+        let resolution = resolve_symlink(Path::new(&self.root));
+        match resolution.exists && resolution.target.is_dir() {
+            true => normalize(&resolution.target),
+            false => self.root.clone(),
+        }
+    }
+
+    /// `info` found below `scan_root` with its `path` moved back below the root, so
+    /// the entries of a resolved root keep the link's name.
+    fn under_root(&self, scan_root: &str, mut info: FileInfo) -> FileInfo {
+        if let Ok(relative) = Path::new(&info.path).strip_prefix(scan_root) {
+            info.path = normalize(&Path::new(&self.root).join(relative));
+        }
+        info
+    }
+
+    /// Store the entries of a scan of `scan_root` as the result.
+    fn set_results(&mut self, scan_root: &str, results: Option<Vec<FileInfo>>) {
+        let results = results.map(|results| {
+            results
+                .into_iter()
+                .map(|info| self.under_root(scan_root, info))
+                .collect()
+        });
+        self.files = results;
+    }
+
+    /// Scan at most `limit` entries, returning a checkpoint if the scan is unfinished.
+    ///
+    /// ```
+    /// # use clannad::filter::{Filter, SymlinkFilter};
+    /// # use std::path::Path;
+    /// let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    /// let checkpoint = filter.scan_until(2).unwrap();
+    /// assert!(filter.files().is_none());
+    /// filter.resume_scan(checkpoint);
+    /// assert_eq!(filter.into_iter().len(), 8);
+    /// ```
+    pub fn scan_until(&mut self, limit: usize) -> Option<ScanCheckpoint> {
+        self.files = None;
+        let mut walk = DirWalk::scan(&self.scan_root(), self.policy(), &self.options)?;
+        let results = walk.by_ref().take(limit).collect();
+        self.timed_out = walk.timed_out();
+        self.finish_walk(ScanCheckpoint {
+            queue: walk.into_queue(),
+            results,
+        })
+    }
+
+    /// Continue an interrupted scan until it completes.
+    ///
+    /// Queued paths removed since the checkpoint was taken are skipped.
+    pub fn resume_scan(&mut self, mut checkpoint: ScanCheckpoint) {
+        let mut walk = DirWalk::resume(checkpoint.queue, self.policy(), &self.options);
+        checkpoint.results.extend(walk.by_ref());
+        self.timed_out = walk.timed_out();
+        checkpoint.queue = walk.into_queue();
+        match self.timed_out {
+            true => self.set_results(&self.scan_root(), Some(checkpoint.results)),
+            false => {
+                self.finish_walk(checkpoint);
+            }
+        }
+    }
+
+    fn finish_walk(&mut self, checkpoint: ScanCheckpoint) -> Option<ScanCheckpoint> {
+        if checkpoint.queue.is_empty() {
+            self.set_results(&self.scan_root(), Some(checkpoint.results));
+            None
+        } else {
+            Some(checkpoint)
+        }
+    }
+}
+
+impl Filter for SymlinkFilter {
+    fn new(root: &Path) -> Self {
+        Self {
+            root: normalize(root),
+            files: None,
+            options: ScanOptions::default(),
+            timed_out: false,
+        }
+    }
+    fn scan(&mut self) {
+        let root = self.scan_root();
+        let mut walk = DirWalk::scan(&root, self.policy(), &self.options);
+        let results = walk.as_mut().map(|walk| walk.collect());
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+        self.set_results(&root, results);
+    }
+
+    fn scan_parallel(&mut self, threads: usize) {
+        let walker = Walker::new(self.policy(), &self.options);
+        let root = self.scan_root();
+        let results = walker.list_files_parallel(&root, threads);
+        self.timed_out = walker.timed_out.load(Ordering::Relaxed);
+        self.set_results(&root, results);
+    }
+
+    fn scan_each(&mut self, cb: &mut dyn FnMut(FileInfo)) {
+        let root = self.scan_root();
+        let mut walk = DirWalk::scan(&root, self.policy(), &self.options);
+        self.files = None;
+        if let Some(walk) = walk.as_mut() {
+            walk.for_each(|info| cb(self.under_root(&root, info)));
+        }
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+    }
+    fn files(&self) -> &Option<Vec<FileInfo>> {
+        &self.files
+    }
+    fn update(self, root: &Path) -> Self {
+        let mut instance = self;
+        instance.root = normalize(root);
+        instance.files = None;
+        instance.timed_out = false;
+        instance
+    }
+
+    fn update_keeping_cache(self, root: &Path) -> Self {
+        let cached = match self.timed_out {
+            true => None,
+            false => cached_subtree(&self.files, root),
+        };
+        let mut instance = self.update(root);
+        instance.files = cached;
+        instance
+    }
+
+    fn restrict_to(&mut self, subpaths: &[&Path]) {
+        restrict(&mut self.files, &self.root, subpaths);
+    }
+
+    fn options_mut(&mut self) -> &mut ScanOptions {
+        &mut self.options
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    fn validate(&self) -> Result<(), RootError> {
+        validate_root(Path::new(&self.root))
+    }
+
+    fn would_include(&self, path: &Path) -> bool {
+        self.options.would_include(&self.scan_root(), path)
+    }
+}
+
+impl IntoIterator for SymlinkFilter {
+    type Item = FileInfo;
+    type IntoIter = IntoIter<FileInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.unwrap_or(Vec::new()).into_iter()
+    }
+}
+
+impl SymlinkFollowFilter {
+    /// Follow only the symlinks whose resolved entry satisfies `predicate`, retaining
+    /// the others as [`SymlinkFilter`] does. All symlinks are followed by default.
+    ///
+    /// The predicate may be called from several threads by `scan_parallel`.
+    ///
+    /// ```
+    /// # use clannad::filter::{Filter, SymlinkFollowFilter};
+    /// # use std::path::Path;
+    /// let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+    /// filter.follow_if(Box::new(|f| !f.content_path.ends_with("level1")));
+    /// filter.scan();
+    /// assert_eq!(filter.into_iter().len(), 8);
+    /// ```
+    pub fn follow_if(&mut self, predicate: Box<dyn Fn(&FileInfo) -> bool + Send + Sync>) {
+        self.options.follow_if = Some(Arc::from(predicate));
+    }
+
+    /// Whether symlinks to directories are followed and their targets traversed, the
+    /// default. Otherwise they are retained as symlink entries like [`SymlinkFilter`]
+    /// records them, while symlinks to files are still followed.
+    ///
+    /// This takes precedence over [`follow_if`](Self::follow_if) for directory targets.
+    ///
+    /// ```
+    /// # use clannad::filter::{Filter, SymlinkFollowFilter};
+    /// # use std::path::Path;
+    /// let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+    /// filter.descend_dir_links(false);
+    /// filter.scan();
+    /// assert_eq!(filter.into_iter().len(), 8);
+    /// ```
+    pub fn descend_dir_links(&mut self, descend: bool) {
+        self.options.retain_dir_links = !descend;
+    }
+
+    /// Record symlinks to directories as symlink entries and also traverse their targets,
+    /// whose entries are listed under the target's own path, such as `root/../shared`
+    /// for a link to `../shared`. Off by default.
+    ///
+    /// A target is traversed once, and never when it is an already entered directory such
+    /// as an ancestor. A target inside the tree which has not been reached yet is still
+    /// traversed again where it lies, so its content can be recorded twice. This takes
+    /// precedence over [`descend_dir_links`](Self::descend_dir_links).
+    pub fn keep_dir_links(&mut self, keep: bool) {
+        self.options.keep_dir_links = keep;
+    }
+
+    /// Follow chains of at most `hops` symlinks, recording longer ones as
+    /// `FileType::NONE` like dangling links. Chains are cut after 40 links by default.
+    pub fn max_symlink_hops(&mut self, hops: usize) {
+        self.options.max_symlink_hops = Some(hops);
+    }
+
+    /// Only follow symlinks resolving inside the root, retaining the others as
+    /// [`SymlinkFilter`] does, so a link such as `../../etc/passwd` cannot pull in files
+    /// from elsewhere. Off by default.
+    ///
+    /// Targets are compared with the root as canonical absolute paths, and dangling links
+    /// are retained too.
+    pub fn follow_within_root(&mut self, within: bool) {
+        self.options.within_root = within;
+    }
+
+    /// Canonicalize the root for `follow_within_root` before a scan.
+    fn bind_root(&mut self) {
+        self.options.root_bound = match self.options.within_root {
+            true => {
+                Some(fs::canonicalize(&self.root).unwrap_or_else(|_| PathBuf::from(&self.root)))
+            }
+            false => None,
+        };
+    }
+}
+
+impl Filter for SymlinkFollowFilter {
+    fn new(root: &Path) -> Self {
+        Self {
+            root: normalize(root),
+            files: None,
+            options: ScanOptions::default(),
+            timed_out: false,
         }
     }
-    fn follow_link(symlink: &str) -> FileInfo {
-        let symlink_path = Path::new(symlink);
-        let mut destination_path = symlink_path
-            .read_link()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_owned();
-        while Path::new(&destination_path).is_symlink() {
-            destination_path = Path::new(&destination_path)
-                .read_link()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
-        }
-        let destination_path = Path::new(symlink_path)
-            .parent()
-            .unwrap()
-            .join(Path::new(&destination_path));
-        FileInfo::new(
-            symlink_path,
-            destination_path.as_path(),
-            if destination_path.is_dir() {
-                FileType::DIRECTORY
-            } else {
-                FileType::REGULAR
-            },
-            None,
-        )
+
+    fn scan(&mut self) {
+        self.bind_root();
+        let mut walk = DirWalk::scan(&self.root, SymlinkPolicy::Follow, &self.options);
+        self.files = walk.as_mut().map(|walk| walk.collect());
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+    }
+
+    fn scan_parallel(&mut self, threads: usize) {
+        self.bind_root();
+        let walker = Walker::new(SymlinkPolicy::Follow, &self.options);
+        self.files = walker.list_files_parallel(&self.root, threads);
+        self.timed_out = walker.timed_out.load(Ordering::Relaxed);
+    }
+
+    fn scan_each(&mut self, cb: &mut dyn FnMut(FileInfo)) {
+        self.bind_root();
+        let mut walk = DirWalk::scan(&self.root, SymlinkPolicy::Follow, &self.options);
+        self.files = None;
+        if let Some(walk) = walk.as_mut() {
+            walk.for_each(cb);
+        }
+        self.timed_out = walk.is_some_and(|walk| walk.timed_out());
+    }
+
+    fn files(&self) -> &Option<Vec<FileInfo>> {
+        &self.files
+    }
+
+    fn update(self, root: &Path) -> Self {
+        let mut instance = self;
+        instance.root = normalize(root);
+        instance.files = None;
+        instance.timed_out = false;
+        instance
+    }
+
+    fn update_keeping_cache(self, root: &Path) -> Self {
+        let cached = match self.timed_out {
+            true => None,
+            false => cached_subtree(&self.files, root),
+        };
+        let mut instance = self.update(root);
+        instance.files = cached;
+        instance
+    }
+
+    fn restrict_to(&mut self, subpaths: &[&Path]) {
+        restrict(&mut self.files, &self.root, subpaths);
+    }
+
+    fn options_mut(&mut self) -> &mut ScanOptions {
+        &mut self.options
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    fn validate(&self) -> Result<(), RootError> {
+        validate_root(Path::new(&self.root))
+    }
+
+    fn would_include(&self, path: &Path) -> bool {
+        self.options.would_include(&self.root, path)
+    }
+}
+
+impl IntoIterator for SymlinkFollowFilter {
+    type Item = FileInfo;
+    type IntoIter = IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.unwrap_or(Vec::new()).into_iter()
+    }
+}
+
+/// The filter lists the entries of a zip archive instead of a directory, so an existing
+/// archive can be filtered again and repacked.
+///
+/// `root` is the archive file. The `path` and `content_path` of each entry are its name
+/// inside the archive, without the trailing `/` of directories, and its content is read
+/// with [`ZipArchive::by_name`], which a `ZipDeflate` given the archive through
+/// `with_source_archive` does to repack them. Symlink entries carry their target in
+/// `symlink_path`.
+/// Entries have no owner, and recording absolute paths has no effect on them.
+pub struct ArchiveFilter {
+    root: String,
+    files: Option<Vec<FileInfo>>,
+    options: ScanOptions,
+    timed_out: bool,
+}
+
+impl ArchiveFilter {
+    fn list_entries(&mut self) -> Option<Vec<FileInfo>> {
+        let walker = Walker::new(SymlinkPolicy::Retain, &self.options);
+        let _permit = self
+            .options
+            .limiter
+            .as_ref()
+            .map(|limiter| limiter.acquire());
+        let mut archive = ZipArchive::new(File::open(&self.root).ok()?).ok()?;
+        let mut results = Vec::new();
+        for i in 0..archive.len() {
+            if walker.past_deadline() {
+                self.timed_out = true;
+                break;
+            }
+            let Ok(mut entry) = archive.by_index(i) else {
+                continue;
+            };
+            let name = entry.name().trim_end_matches('/').to_owned();
+            if name.is_empty() || !self.options.would_include("", Path::new(&name)) {
+                continue;
+            }
+            let file_type = if entry.is_dir() {
+                FileType::DIRECTORY
+            } else if entry.is_symlink() {
+                FileType::SYMLINK
+            } else {
+                FileType::REGULAR
+            };
+            let symlink_path = match file_type {
+                FileType::SYMLINK => {
+                    let mut target = String::new();
+                    entry.read_to_string(&mut target).ok().map(|_| target)
+                }
+                _ => None,
+            };
+            results.push(FileInfo {
+                path: name.clone(),
+                content_path: name,
+                symlink_path,
+                size: match file_type {
+                    FileType::REGULAR => entry.size(),
+                    _ => 0,
+                },
+                file_type,
+                readonly: entry.unix_mode().is_some_and(|mode| mode & 0o222 == 0),
+                uid: None,
+                gid: None,
+                abs_path: None,
+                flags: None,
+                mount_point: None,
+            });
+        }
+        Some(results)
+    }
+}
+
+impl Filter for ArchiveFilter {
+    fn new(root: &Path) -> Self {
+        Self {
+            root: normalize(root),
+            files: None,
+            options: ScanOptions::default(),
+            timed_out: false,
+        }
+    }
+
+    fn scan(&mut self) {
+        self.timed_out = false;
+        self.files = self.list_entries();
+    }
+
+    /// The central directory is read as a whole, so the entries are handed on after that.
+    fn scan_each(&mut self, cb: &mut dyn FnMut(FileInfo)) {
+        self.timed_out = false;
+        self.files = None;
+        self.list_entries().into_iter().flatten().for_each(cb);
+    }
+
+    fn files(&self) -> &Option<Vec<FileInfo>> {
+        &self.files
+    }
+
+    fn update(self, root: &Path) -> Self {
+        let mut instance = self;
+        instance.root = normalize(root);
+        instance.files = None;
+        instance.timed_out = false;
+        instance
+    }
+
+    fn restrict_to(&mut self, subpaths: &[&Path]) {
+        restrict(&mut self.files, "", subpaths);
+    }
+
+    fn options_mut(&mut self) -> &mut ScanOptions {
+        &mut self.options
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    fn validate(&self) -> Result<(), RootError> {
+        validate_root(Path::new(&self.root))
+    }
+
+    /// Whether an entry named `path` would be listed, checked against the rules only.
+    fn would_include(&self, path: &Path) -> bool {
+        self.options.would_include("", path)
+    }
+}
+
+impl IntoIterator for ArchiveFilter {
+    type Item = FileInfo;
+    type IntoIter = IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.unwrap_or(Vec::new()).into_iter()
+    }
+}
+
+/// Scan `root` with `policy`, sending each entry through `sender` as soon as it is found.
+///
+/// Returns `false` without sending anything if the root does not exist. Sending stops
+/// early once the receiver is dropped.
+pub fn scan_to(
+    root: &Path,
+    policy: SymlinkPolicy,
+    excludes: &[String],
+    sender: SyncSender<FileInfo>,
+) -> bool {
+    let options = ScanOptions {
+        excludes: excludes.to_vec(),
+        ..ScanOptions::default()
+    };
+    let root = root.to_str().expect("invalid path");
+    let Some(walk) = DirWalk::scan(root, policy, &options) else {
+        return false;
+    };
+    for info in walk {
+        if sender.send(info).is_err() {
+            break;
+        }
+    }
+    true
+}
+
+/// Pair each entry with its content, read only when the item is reached.
+///
+/// Directories, symlinks kept as symlinks and other non-regular entries get empty content.
+pub fn with_contents(
+    iter: impl Iterator<Item = FileInfo>,
+) -> impl Iterator<Item = (FileInfo, io::Result<Vec<u8>>)> {
+    iter.map(|f| {
+        let content = match (&f.symlink_path, f.file_type) {
+            (None, FileType::REGULAR) => fs::read(&f.content_path),
+            _ => Ok(Vec::new()),
+        };
+        (f, content)
+    })
+}
+
+/// Whether `a` and `b` hold the same content, such as a source entry and its extracted copy.
+///
+/// Regular files are compared byte by byte, symlinks kept as symlinks by their target and
+/// directories by type alone. Paths and metadata are not compared.
+pub fn content_equal(a: &FileInfo, b: &FileInfo) -> io::Result<bool> {
+    match (&a.symlink_path, &b.symlink_path) {
+        (Some(a), Some(b)) => return Ok(a == b),
+        (None, None) => (),
+        _ => return Ok(false),
+    }
+    if a.file_type != b.file_type {
+        return Ok(false);
+    }
+    if a.file_type != FileType::REGULAR {
+        return Ok(true);
+    }
+    let (mut a, mut b) = (File::open(&a.content_path)?, File::open(&b.content_path)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut chunk_a, mut chunk_b) = (vec![0; CONTENT_CHUNK_LEN], vec![0; CONTENT_CHUNK_LEN]);
+    loop {
+        let len = read_chunk(&mut a, &mut chunk_a)?;
+        if len != read_chunk(&mut b, &mut chunk_b)? || chunk_a[..len] != chunk_b[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Bytes of each file held at once by `content_equal`.
+const CONTENT_CHUNK_LEN: usize = 64 * 1024;
+
+/// Fill `buf` from `reader` as far as it goes, returning the bytes read.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+pub fn scan_symlink(root: &Path) -> Option<Vec<FileInfo>> {
+    let mut filter = SymlinkFilter::new(root);
+    filter.scan();
+    if let &None = filter.files() {
+        return None;
+    }
+    Some(filter.into_iter().collect())
+}
+
+pub fn scan_symlink_follow(root: &Path) -> Option<Vec<FileInfo>> {
+    let mut filter = SymlinkFollowFilter::new(root);
+    filter.scan();
+    if let &None = filter.files() {
+        return None;
+    }
+    Some(filter.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_required_methods() {
+        // a filter outside the crate only implements the required methods
+        struct ListFilter {
+            root: PathBuf,
+            files: Option<Vec<FileInfo>>,
+            options: ScanOptions,
+        }
+        impl Filter for ListFilter {
+            fn new(root: &Path) -> Self {
+                Self {
+                    root: root.to_path_buf(),
+                    files: None,
+                    options: ScanOptions::default(),
+                }
+            }
+            fn scan(&mut self) {
+                self.files = Some(vec![FileInfo::new(
+                    &self.root,
+                    &self.root,
+                    FileType::DIRECTORY,
+                    None,
+                )]);
+            }
+            fn files(&self) -> &Option<Vec<FileInfo>> {
+                &self.files
+            }
+            fn update(self, root: &Path) -> Self {
+                Self::new(root)
+            }
+            fn options_mut(&mut self) -> &mut ScanOptions {
+                &mut self.options
+            }
+            fn restrict_to(&mut self, _subpaths: &[&Path]) {}
+            fn would_include(&self, path: &Path) -> bool {
+                path == self.root
+            }
+            fn validate(&self) -> Result<(), RootError> {
+                validate_root(&self.root)
+            }
+        }
+        impl IntoIterator for ListFilter {
+            type Item = FileInfo;
+            type IntoIter = IntoIter<FileInfo>;
+            fn into_iter(self) -> Self::IntoIter {
+                self.files.unwrap_or_default().into_iter()
+            }
+        }
+
+        let mut filter = ListFilter::new(Path::new("resources"));
+        filter.exclude(&["*.tmp".to_owned()]);
+        filter.timeout(Duration::from_secs(1));
+        assert_eq!(filter.options.excludes, ["*.tmp"]);
+        let mut found = 0;
+        filter.scan_each(&mut |_| found += 1);
+        assert_eq!(found, 1);
+        assert!(!filter.timed_out());
+        assert!(filter
+            .update_keeping_cache(Path::new("src"))
+            .files
+            .is_none());
+    }
+
+    #[test]
+    fn basic_filter() {
+        let mut filter = BasicFilter::new(Path::new("dst"));
+        filter.scan();
+        assert_eq!(filter.files().is_none(), true);
+        filter = filter.update(Path::new("resources/normalfolder"));
+        filter.scan();
+        assert_eq!(filter.into_iter().len(), 8 as usize);
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(
+            BasicFilter::new(Path::new("dst")).validate(),
+            Err(RootError::NotFound)
+        );
+        assert_eq!(
+            SymlinkFilter::new(Path::new("resources/normalfolder")).validate(),
+            Ok(())
+        );
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            assert_eq!(
+                validate_root(Path::new(OsStr::from_bytes(b"dst\xff"))),
+                Err(RootError::NotUtf8)
+            );
+        }
+    }
+
+    #[test]
+    fn basic_filter_symlink_root() {
+        let mut filter = BasicFilter::new(Path::new("resources/normalsymlink"));
+        filter.scan();
+        assert_eq!(filter.into_iter().len(), 1 as usize);
+    }
+
+    #[test]
+    fn symlink_filter() {
+        let mut filter = SymlinkFilter::new(Path::new("dst"));
+        filter.scan();
+        assert_eq!(filter.files().is_none(), true);
+        filter = filter.update(Path::new("resources/normalfolder"));
+        filter.scan();
+        assert_eq!(
+            filter
+                .files()
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|x| x.symlink_path.is_some())
+                .count(),
+            3
+        );
+        assert_eq!(filter.into_iter().len(), 8 as usize);
+    }
+
+    #[test]
+    fn symlink_filter_symlink_root() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalsymlink"));
+        filter.scan();
+        assert_eq!(filter.into_iter().len(), 1 as usize);
+    }
+
+    #[test]
+    fn symlink_follow_filter() {
+        let mut filter = SymlinkFollowFilter::new(Path::new("dst"));
+        filter.scan();
+        assert_eq!(filter.files().is_none(), true);
+        filter = filter.update(Path::new("resources/normalfolder"));
+        filter.scan();
+        assert!(filter
+            .files()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .all(|x| x.symlink_path.is_none()));
+        assert_eq!(filter.into_iter().len(), 10 as usize);
+    }
+
+    #[test]
+    fn symlink_policy_behaviors() {
+        let find = |files: &Option<Vec<FileInfo>>, path: &str| {
+            files
+                .as_ref()
+                .unwrap()
+                .iter()
+                .find(|x| x.path == path)
+                .cloned()
+                .unwrap()
+        };
+        let current = "resources/normalfolder/current";
+
+        let mut basic = BasicFilter::new(Path::new("resources/normalfolder"));
+        basic.scan();
+        let info = find(basic.files(), current);
+        assert_eq!(info.file_type, FileType::REGULAR);
+        assert_eq!(info.symlink_path, None);
+
+        let mut retain = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        retain.scan();
+        let info = find(retain.files(), current);
+        assert_eq!(info.symlink_path, Some("level1".to_owned()));
+        assert_eq!(info.content_path, current);
+
+        let mut follow = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        follow.scan();
+        let info = find(follow.files(), current);
+        assert_eq!(info.file_type, FileType::DIRECTORY);
+        assert_eq!(info.content_path, "resources/normalfolder/level1");
+        assert_eq!(
+            follow
+                .files()
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|x| x.path == "resources/normalfolder/level1/test1.ext1")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn glob_exclude() {
+        assert!(glob_match("*.ext2", "test1.ext2"));
+        assert!(!glob_match("*.ext2", "level1/test1.ext2"));
+        assert!(glob_match(
+            "**/test1.*",
+            "resources/normalfolder/level1/test1.ext1"
+        ));
+        assert!(glob_match("test?", "test1"));
+
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.exclude(&["*.ext2".to_owned(), "cur*".to_owned()]);
+        filter.scan();
+        assert!(filter
+            .files()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .all(|x| !x.path.ends_with(".ext2") && !x.path.contains("cur")));
+        assert_eq!(filter.into_iter().len(), 5);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_exclude() {
+        fs::create_dir_all("test_regex_exclude/2024").unwrap();
+        fs::create_dir_all("test_regex_exclude/v2024").unwrap();
+        fs::write("test_regex_exclude/2024/a.txt", "a").unwrap();
+        fs::write("test_regex_exclude/v2024/b.txt", "b").unwrap();
+        fs::write("test_regex_exclude/1999", "c").unwrap();
+        fs::write("test_regex_exclude/keep.txt", "d").unwrap();
+
+        let mut filter = SymlinkFilter::new(Path::new("test_regex_exclude"));
+        filter.exclude_regex(&[Regex::new(r"(^|/)\d{4}$").unwrap()]);
+        filter.scan();
+        let mut paths: Vec<String> = filter.into_iter().map(|f| f.path).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "test_regex_exclude",
+                "test_regex_exclude/keep.txt",
+                "test_regex_exclude/v2024",
+                "test_regex_exclude/v2024/b.txt",
+            ]
+        );
+
+        fs::remove_dir_all("test_regex_exclude").unwrap();
+    }
+
+    #[test]
+    fn would_include() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.exclude(&["*.ext2".to_owned(), "current".to_owned()]);
+        assert!(filter.would_include(Path::new("resources/normalfolder")));
+        assert!(filter.would_include(Path::new("resources/normalfolder/level1/test1.ext1")));
+        assert!(!filter.would_include(Path::new("resources/normalfolder/level1/test1.ext2")));
+        assert!(!filter.would_include(Path::new("resources/normalfolder/current/test1.ext1")));
+        assert!(!filter.would_include(Path::new("resources/other")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_follow_if() {
+        fs::create_dir_all("test_follow_if").unwrap();
+        fs::write("test_follow_if/app.conf", "key=value").unwrap();
+        fs::write("test_follow_if/notes.txt", "notes").unwrap();
+        std::os::unix::fs::symlink("app.conf", "test_follow_if/conf_link").unwrap();
+        std::os::unix::fs::symlink("notes.txt", "test_follow_if/notes_link").unwrap();
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_follow_if"));
+        filter.follow_if(Box::new(|f| f.content_path.ends_with(".conf")));
+        filter.scan();
+        let files = filter.files().as_ref().unwrap();
+        let conf = files
+            .iter()
+            .find(|x| x.path == "test_follow_if/conf_link")
+            .unwrap();
+        assert_eq!(conf.symlink_path, None);
+        assert_eq!(conf.content_path, "test_follow_if/app.conf");
+        let notes = files
+            .iter()
+            .find(|x| x.path == "test_follow_if/notes_link")
+            .unwrap();
+        assert_eq!(notes.symlink_path, Some("notes.txt".to_owned()));
+
+        fs::remove_dir_all("test_follow_if").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_ancestor_loop() {
+        fs::create_dir_all("test_ancestor_loop/sub").unwrap();
+        std::os::unix::fs::symlink("..", "test_ancestor_loop/sub/link").unwrap();
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_ancestor_loop"));
+        filter.scan();
+        let files = filter.files().as_ref().unwrap();
+        assert_eq!(files.len(), 3);
+        let link = files
+            .iter()
+            .find(|x| x.path == "test_ancestor_loop/sub/link")
+            .unwrap();
+        assert_eq!(link.file_type, FileType::DIRECTORY);
+
+        fs::remove_dir_all("test_ancestor_loop").unwrap();
     }
-}
 
-impl IntoIterator for SymlinkFollowFilter {
-    type Item = FileInfo;
-    type IntoIter = IntoIter<Self::Item>;
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlink_chain() {
+        let good = resolve_symlink(Path::new("resources/normalfolder/curtest"));
+        assert_eq!(
+            good.target,
+            Path::new("resources/normalfolder/level1/test1.ext1")
+        );
+        assert!(good.exists);
+        assert!(!good.looped);
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.files.unwrap_or(Vec::new()).into_iter()
+        fs::create_dir_all("test_resolve_symlink").unwrap();
+        std::os::unix::fs::symlink("missing", "test_resolve_symlink/broken").unwrap();
+        std::os::unix::fs::symlink("loop_b", "test_resolve_symlink/loop_a").unwrap();
+        std::os::unix::fs::symlink("loop_a", "test_resolve_symlink/loop_b").unwrap();
+
+        let broken = resolve_symlink(Path::new("test_resolve_symlink/broken"));
+        assert_eq!(broken.target, Path::new("test_resolve_symlink/missing"));
+        assert!(!broken.exists);
+        assert!(!broken.looped);
+
+        let looped = resolve_symlink(Path::new("test_resolve_symlink/loop_a"));
+        assert!(!looped.exists);
+        assert!(looped.looped);
+
+        fs::remove_dir_all("test_resolve_symlink").unwrap();
     }
-}
 
-pub fn scan_symlink(root: &Path) -> Option<Vec<FileInfo>> {
-    let mut filter = SymlinkFilter::new(root);
-    filter.scan();
-    if let &None = filter.files() {
-        return None;
+    #[test]
+    fn symlink_follow_filter_retain_dir_links() {
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        filter.descend_dir_links(false);
+        filter.scan();
+        let files = filter.files().as_ref().unwrap();
+        let current = files
+            .iter()
+            .find(|x| x.path == "resources/normalfolder/current")
+            .unwrap();
+        assert_eq!(current.symlink_path, Some("level1".to_owned()));
+        assert!(!files
+            .iter()
+            .any(|x| x.path.starts_with("resources/normalfolder/current/")));
+        let test = files
+            .iter()
+            .find(|x| x.path == "resources/normalfolder/test")
+            .unwrap();
+        assert_eq!(test.symlink_path, None);
+        assert_eq!(test.file_type, FileType::REGULAR);
     }
-    Some(filter.into_iter().collect())
-}
 
-pub fn scan_symlink_follow(root: &Path) -> Option<Vec<FileInfo>> {
-    let mut filter = SymlinkFollowFilter::new(root);
-    filter.scan();
-    if let &None = filter.files() {
-        return None;
+    #[cfg(unix)]
+    #[test]
+    fn content_equal_files() {
+        fs::create_dir_all("test_content_equal").unwrap();
+        let big: Vec<u8> = (0..CONTENT_CHUNK_LEN * 2 + 7).map(|i| i as u8).collect();
+        let mut changed = big.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        fs::write("test_content_equal/a", &big).unwrap();
+        fs::write("test_content_equal/b", &big).unwrap();
+        fs::write("test_content_equal/c", &changed).unwrap();
+        fs::write("test_content_equal/d", &big[1..]).unwrap();
+        let info = |name: &str| {
+            let path = Path::new("test_content_equal").join(name);
+            FileInfo::new(&path, &path, FileType::from_path(&path), None)
+        };
+        assert!(content_equal(&info("a"), &info("b")).unwrap());
+        assert!(!content_equal(&info("a"), &info("c")).unwrap());
+        assert!(!content_equal(&info("a"), &info("d")).unwrap());
+        assert!(!content_equal(&info("a"), &info("")).unwrap());
+        assert!(content_equal(&info(""), &info("")).unwrap());
+
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let files: Vec<FileInfo> = filter.into_iter().collect();
+        let find = |path: &str| files.iter().find(|f| f.path.ends_with(path)).unwrap();
+        assert!(content_equal(find("/test"), find("/test")).unwrap());
+        assert!(!content_equal(find("/test"), find("/curtest")).unwrap());
+        assert!(!content_equal(find("/test"), find("/test1.ext1")).unwrap());
+        fs::remove_dir_all("test_content_equal").unwrap();
     }
-    Some(filter.into_iter().collect())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn expand() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand_path("$HOME/docs"), format!("{}/docs", home));
+        assert_eq!(expand_path("${HOME}docs"), format!("{}docs", home));
+        assert_eq!(expand_path("~/docs"), format!("{}/docs", home));
+        assert_eq!(expand_path("~"), home);
+        for literal in [
+            "a~/b",
+            "~user/docs",
+            "$CLANNAD_UNSET_VAR/docs",
+            "$",
+            "${HOME",
+            "a$/b",
+        ] {
+            assert_eq!(expand_path(literal), literal);
+        }
+    }
 
     #[test]
-    fn basic_filter() {
-        let mut filter = BasicFilter::new(Path::new("dst"));
+    fn scan_each() {
+        let mut scanned = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        scanned.scan();
+        let scanned: Vec<FileInfo> = scanned.into_iter().collect();
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        let mut seen = Vec::new();
+        filter.scan_each(&mut |info| seen.push(info.path));
+        assert_eq!(seen.len(), scanned.len());
+        assert!(seen.iter().zip(&scanned).all(|(seen, f)| *seen == f.path));
+        assert!(filter.files().is_none());
+
+        let mut count = 0;
+        BasicFilter::new(Path::new("dst")).scan_each(&mut |_| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_filter_resolve_root() {
+        fs::create_dir_all("test_resolve_root").unwrap();
+        std::os::unix::fs::symlink("../resources/normalfolder", "test_resolve_root/root").unwrap();
+        let mut filter = SymlinkFilter::new(Path::new("test_resolve_root/root"));
         filter.scan();
-        assert_eq!(filter.files().is_none(), true);
-        filter = filter.update(Path::new("resources/normalfolder"));
+        assert_eq!(filter.files().as_ref().unwrap().len(), 1);
+
+        let mut target = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        target.scan();
+        let target: Vec<FileInfo> = target.into_iter().collect();
+        let mut filter = SymlinkFilter::new(Path::new("test_resolve_root/root"));
+        filter.resolve_root(true);
         filter.scan();
-        assert_eq!(filter.into_iter().len(), 8 as usize);
+        let files: Vec<FileInfo> = filter.into_iter().collect();
+        assert_eq!(files.len(), target.len());
+        assert_eq!(files[0].path, "test_resolve_root/root");
+        assert_eq!(
+            files[0].content_path,
+            "test_resolve_root/../resources/normalfolder"
+        );
+        assert_eq!(files[0].file_type, FileType::DIRECTORY);
+        for (f, t) in files.iter().zip(&target) {
+            let relative = Path::new(&f.path)
+                .strip_prefix("test_resolve_root/root")
+                .unwrap();
+            assert_eq!(
+                Path::new(&f.content_path),
+                Path::new("test_resolve_root/../resources/normalfolder").join(relative)
+            );
+            assert_eq!(f.symlink_path, t.symlink_path);
+        }
+        let links = |files: &[FileInfo]| files.iter().filter(|f| f.symlink_path.is_some()).count();
+        assert_eq!(links(&files), links(&target));
+        fs::remove_dir_all("test_resolve_root").unwrap();
     }
 
     #[test]
-    fn basic_filter_symlink_root() {
-        let mut filter = BasicFilter::new(Path::new("resources/normalsymlink"));
+    fn metadata_calls() {
+        fs::create_dir_all("test_metadata_calls/sub").unwrap();
+        fs::write("test_metadata_calls/a.txt", "a").unwrap();
+        fs::write("test_metadata_calls/sub/b.txt", "b").unwrap();
+        let mut filter = SymlinkFilter::new(Path::new("test_metadata_calls"));
+        METADATA_CALLS.with(|calls| calls.set(0));
         filter.scan();
-        assert_eq!(filter.into_iter().len(), 1 as usize);
+        let calls = METADATA_CALLS.with(|calls| calls.get());
+        let files: Vec<FileInfo> = filter.into_iter().collect();
+        assert_eq!(files.len(), 4);
+        assert_eq!(calls, files.len());
+        assert!(files
+            .iter()
+            .any(|f| f.path.ends_with("a.txt") && f.size == 1));
+        fs::remove_dir_all("test_metadata_calls").unwrap();
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn symlink_filter() {
-        let mut filter = SymlinkFilter::new(Path::new("dst"));
+    fn mount_point() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+35 22 0:31 / /home rw,relatime shared:2 - ext4 /dev/sda2 rw
+36 35 0:32 /data /home/user/my\\040disk rw - ext4 /dev/sdb1 rw
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts, ["/", "/home", "/home/user/my disk"]);
+        let mount = |path: &str| mount_point_of(Path::new(path), &mounts);
+        assert_eq!(mount("/etc/hosts"), Some("/".to_owned()));
+        assert_eq!(mount("/home/user/notes"), Some("/home".to_owned()));
+        assert_eq!(mount("/homework"), Some("/".to_owned()));
+        assert_eq!(
+            mount("/home/user/my disk/photo.jpg"),
+            Some("/home/user/my disk".to_owned())
+        );
+
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.record_mount_point(true);
         filter.scan();
-        assert_eq!(filter.files().is_none(), true);
-        filter = filter.update(Path::new("resources/normalfolder"));
+        assert!(filter
+            .into_iter()
+            .all(|f| f.mount_point.is_some_and(|m| m.starts_with('/'))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_absolute_hops() {
+        use std::os::unix::fs::symlink;
+        fs::create_dir_all("test_absolute_hops/root").unwrap();
+        fs::create_dir_all("test_absolute_hops/elsewhere").unwrap();
+        fs::write("test_absolute_hops/elsewhere/target.txt", "target").unwrap();
+        let elsewhere = fs::canonicalize("test_absolute_hops/elsewhere").unwrap();
+        let target = elsewhere.join("target.txt");
+        // absolute link, and a chain of relative, absolute then relative hops
+        symlink(&target, "test_absolute_hops/root/link").unwrap();
+        symlink(elsewhere.join("hop"), "test_absolute_hops/root/chain").unwrap();
+        symlink("target.txt", "test_absolute_hops/elsewhere/hop").unwrap();
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_absolute_hops/root"));
+        filter.scan();
+        let files: Vec<FileInfo> = filter.into_iter().collect();
+        for name in ["/link", "/chain"] {
+            let followed = files.iter().find(|f| f.path.ends_with(name)).unwrap();
+            assert_eq!(followed.content_path, target.to_str().unwrap());
+            assert_eq!(followed.file_type, FileType::REGULAR);
+        }
+        fs::remove_dir_all("test_absolute_hops").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_within_root() {
+        use std::os::unix::fs::symlink;
+        fs::create_dir_all("test_within_root/root/sub").unwrap();
+        fs::create_dir_all("test_within_root/root_sibling").unwrap();
+        fs::write("test_within_root/secret.txt", "secret").unwrap();
+        fs::write("test_within_root/root/file.txt", "file").unwrap();
+        fs::write("test_within_root/root_sibling/x.txt", "sibling").unwrap();
+        symlink("../../secret.txt", "test_within_root/root/sub/escape").unwrap();
+        symlink("../../root/file.txt", "test_within_root/root/sub/back").unwrap();
+        symlink("../root_sibling/x.txt", "test_within_root/root/sibling").unwrap();
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_within_root/root"));
+        filter.follow_within_root(true);
         filter.scan();
+        let files: Vec<FileInfo> = filter.into_iter().collect();
+        let find = |name: &str| files.iter().find(|f| f.path.ends_with(name)).unwrap();
         assert_eq!(
+            find("/escape").symlink_path.as_deref(),
+            Some("../../secret.txt")
+        );
+        assert_eq!(
+            find("/sibling").symlink_path.as_deref(),
+            Some("../root_sibling/x.txt")
+        );
+        assert_eq!(find("/back").symlink_path, None);
+        assert_eq!(find("/back").file_type, FileType::REGULAR);
+        assert!(!files.iter().any(|f| f.content_path.ends_with("secret.txt")));
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_within_root/root"));
+        filter.scan();
+        assert!(filter
+            .into_iter()
+            .any(|f| f.path.ends_with("/escape") && f.content_path.ends_with("secret.txt")));
+        fs::remove_dir_all("test_within_root").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_keep_dir_links() {
+        fs::create_dir_all("test_keep_dir_links/root").unwrap();
+        fs::create_dir_all("test_keep_dir_links/shared").unwrap();
+        fs::write("test_keep_dir_links/shared/file.txt", "shared").unwrap();
+        std::os::unix::fs::symlink("../shared", "test_keep_dir_links/root/link").unwrap();
+        std::os::unix::fs::symlink(".", "test_keep_dir_links/root/loop").unwrap();
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_keep_dir_links/root"));
+        filter.keep_dir_links(true);
+        filter.scan();
+        let mut files: Vec<_> = filter
+            .into_iter()
+            .map(|f| (f.path, f.symlink_path))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("test_keep_dir_links/root".to_owned(), None),
+                ("test_keep_dir_links/root/../shared".to_owned(), None),
+                (
+                    "test_keep_dir_links/root/../shared/file.txt".to_owned(),
+                    None
+                ),
+                (
+                    "test_keep_dir_links/root/link".to_owned(),
+                    Some("../shared".to_owned())
+                ),
+                (
+                    "test_keep_dir_links/root/loop".to_owned(),
+                    Some(".".to_owned())
+                ),
+            ]
+        );
+
+        fs::remove_dir_all("test_keep_dir_links").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_follow_filter_max_hops() {
+        fs::create_dir_all("test_max_hops").unwrap();
+        fs::write("test_max_hops/file.txt", "123456").unwrap();
+        std::os::unix::fs::symlink("file.txt", "test_max_hops/c").unwrap();
+        std::os::unix::fs::symlink("c", "test_max_hops/b").unwrap();
+        std::os::unix::fs::symlink("b", "test_max_hops/a").unwrap();
+
+        let file_type = |hops| {
+            let mut filter = SymlinkFollowFilter::new(Path::new("test_max_hops"));
+            filter.max_symlink_hops(hops);
+            filter.scan();
             filter
-                .files()
-                .as_ref()
+                .into_iter()
+                .find(|x| x.path == "test_max_hops/a")
                 .unwrap()
-                .iter()
-                .filter(|x| x.symlink_path.is_some())
-                .count(),
-            3
+                .file_type
+        };
+        assert_eq!(file_type(2), FileType::NONE);
+        assert_eq!(file_type(3), FileType::REGULAR);
+
+        fs::remove_dir_all("test_max_hops").unwrap();
+    }
+
+    #[test]
+    fn file_type_classify() {
+        assert_eq!(
+            FileType::from_path(Path::new("resources/normalfolder/level1/test1.ext1")),
+            FileType::REGULAR
         );
-        assert_eq!(filter.into_iter().len(), 8 as usize);
+        assert_eq!(
+            FileType::from_path(Path::new("resources/normalfolder/level1")),
+            FileType::DIRECTORY
+        );
+        assert_eq!(
+            FileType::from_path(Path::new("resources/normalfolder/current")),
+            FileType::SYMLINK
+        );
+        assert_eq!(
+            FileType::from_path(Path::new("resources/normalfolder/missing")),
+            FileType::NONE
+        );
+        let followed = fs::metadata("resources/normalfolder/current").unwrap();
+        assert_eq!(
+            FileType::from_metadata(&followed, false),
+            FileType::DIRECTORY
+        );
+        assert_eq!(FileType::from_metadata(&followed, true), FileType::SYMLINK);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn symlink_filter_symlink_root() {
-        let mut filter = SymlinkFilter::new(Path::new("resources/normalsymlink"));
-        filter.scan();
-        assert_eq!(filter.into_iter().len(), 1 as usize);
+    fn file_type_classify_special() {
+        assert_eq!(FileType::from_path(Path::new("/dev/null")), FileType::NONE);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn symlink_follow_filter() {
-        let mut filter = SymlinkFollowFilter::new(Path::new("dst"));
+    fn file_info_ownership() {
+        use std::os::unix::fs::MetadataExt;
+        let path = Path::new("resources/normalfolder/level1/test1.ext1");
+        let metadata = fs::metadata(path).unwrap();
+        let info = FileInfo::new(path, path, FileType::REGULAR, None);
+        assert_eq!(info.uid, Some(metadata.uid()));
+        assert_eq!(info.gid, Some(metadata.gid()));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[test]
+    fn file_info_flags() {
+        use std::process::Command;
+        // UF_IMMUTABLE, set by `chflags uchg`
+        const IMMUTABLE: u32 = 0x2;
+        fs::write("test_file_flags.txt", "123456").unwrap();
+        let path = Path::new("test_file_flags.txt");
+        let plain = FileInfo::new(path, path, FileType::REGULAR, None);
+        assert_eq!(plain.flags.map(|f| f & IMMUTABLE), Some(0));
+
+        assert!(Command::new("chflags")
+            .args(["uchg", "test_file_flags.txt"])
+            .status()
+            .unwrap()
+            .success());
+        let immutable = FileInfo::new(path, path, FileType::REGULAR, None);
+        Command::new("chflags")
+            .args(["nouchg", "test_file_flags.txt"])
+            .status()
+            .unwrap();
+        assert_eq!(immutable.flags.map(|f| f & IMMUTABLE), Some(IMMUTABLE));
+
+        fs::remove_file("test_file_flags.txt").unwrap();
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    #[test]
+    fn file_info_flags() {
+        let path = Path::new("resources/normalfolder/level1/test1.ext1");
+        assert_eq!(
+            FileInfo::new(path, path, FileType::REGULAR, None).flags,
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filter_timeout() {
+        fs::create_dir_all("test_filter_timeout").unwrap();
+        fs::write("test_filter_timeout/target.txt", "123456").unwrap();
+        for i in 0..10 {
+            let link = format!("test_filter_timeout/link{}", i);
+            std::os::unix::fs::symlink("target.txt", link).unwrap();
+        }
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("test_filter_timeout"));
         filter.scan();
-        assert_eq!(filter.files().is_none(), true);
-        filter = filter.update(Path::new("resources/normalfolder"));
+        assert!(!filter.timed_out());
+        assert_eq!(filter.len(), 12);
+
+        // the first followed link stands for a lookup slower than the whole timeout
+        let lookups = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&lookups);
+        filter.follow_if(Box::new(move |_| {
+            if counter.fetch_add(1, Ordering::Relaxed) == 0 {
+                std::thread::sleep(Duration::from_millis(600));
+            }
+            true
+        }));
+        filter.timeout(Duration::from_millis(500));
+        filter.scan();
+        assert!(filter.timed_out());
+        // stopped at the first check after the slow lookup, keeping what it found
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+        assert!(filter.len() > 1 && filter.len() < 12);
+
+        fs::remove_dir_all("test_filter_timeout").unwrap();
+    }
+
+    #[test]
+    fn record_abs_path() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
         filter.scan();
         assert!(filter
             .files()
             .as_ref()
             .unwrap()
             .iter()
-            .all(|x| x.symlink_path.is_none()));
-        assert_eq!(filter.into_iter().len(), 10 as usize);
+            .all(|f| f.abs_path.is_none()));
+
+        filter.record_abs_path(true);
+        filter.scan();
+        let files = filter.files().as_ref().unwrap();
+        let file = files
+            .iter()
+            .find(|f| f.path == "resources/normalfolder/level1/test1.ext1")
+            .unwrap();
+        let abs_path = file.abs_path.as_ref().unwrap();
+        assert!(Path::new(abs_path).is_absolute());
+        assert!(abs_path.ends_with("resources/normalfolder/level1/test1.ext1"));
+        let link = files
+            .iter()
+            .find(|f| f.path == "resources/normalfolder/test")
+            .unwrap();
+        assert!(link
+            .abs_path
+            .as_ref()
+            .unwrap()
+            .ends_with("resources/normalfolder/test"));
+    }
+
+    #[test]
+    fn filter_len() {
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        assert_eq!(filter.len(), 0);
+        assert!(filter.is_empty());
+        filter.scan();
+        assert_eq!(filter.len(), 10);
+        assert!(!filter.is_empty());
+        assert_eq!(filter.into_iter().count(), 10);
+    }
+
+    #[test]
+    fn symlink_filter_skip_symlinks() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.skip_symlinks(true);
+        filter.scan();
+        let files = filter.files().as_ref().unwrap();
+        assert!(files
+            .iter()
+            .all(|f| f.symlink_path.is_none() && f.file_type != FileType::SYMLINK));
+        let mut paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "resources/normalfolder",
+                "resources/normalfolder/level1",
+                "resources/normalfolder/level1/test1.ext1",
+                "resources/normalfolder/level1/test1.ext2",
+                "resources/normalfolder/test1",
+            ]
+        );
+    }
+
+    #[test]
+    fn dir_walk() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let mut expected: Vec<String> = filter.into_iter().map(|f| f.path).collect();
+        expected.sort();
+        let mut walked: Vec<String> =
+            DirWalk::new(Path::new("resources/normalfolder"), SymlinkPolicy::Retain)
+                .map(|f| f.path)
+                .collect();
+        walked.sort();
+        assert_eq!(walked, expected);
+
+        let shallow = DirWalk::new(Path::new("resources/normalfolder"), SymlinkPolicy::Retain)
+            .with_max_depth(1);
+        assert!(shallow.map(|f| f.path).all(|p| !p.contains("level1/")));
+        let excluded = DirWalk::new(Path::new("resources/normalfolder"), SymlinkPolicy::Follow)
+            .with_excludes(&["*.ext2".to_owned()]);
+        assert_eq!(excluded.count(), 8);
+        assert_eq!(
+            DirWalk::new(Path::new("resources/missing"), SymlinkPolicy::Retain).count(),
+            0
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_parallel() {
+        fs::create_dir_all("test_scan_parallel/a/b/c").unwrap();
+        fs::create_dir_all("test_scan_parallel/d/e").unwrap();
+        for dir in ["", "/a", "/a/b", "/a/b/c", "/d", "/d/e"] {
+            for i in 0..5 {
+                fs::write(format!("test_scan_parallel{}/file{}", dir, i), "123456").unwrap();
+            }
+        }
+        std::os::unix::fs::symlink("a/b", "test_scan_parallel/link").unwrap();
+        std::os::unix::fs::symlink("a/b", "test_scan_parallel/link2").unwrap();
+
+        let mut sequential = SymlinkFollowFilter::new(Path::new("test_scan_parallel"));
+        sequential.scan();
+        for threads in [1, 3, 16] {
+            let mut parallel = SymlinkFollowFilter::new(Path::new("test_scan_parallel"));
+            parallel.scan_parallel(threads);
+            assert_eq!(parallel.files(), sequential.files());
+        }
+        let mut sequential = SymlinkFollowFilter::new(Path::new("test_scan_parallel"));
+        sequential.keep_dir_links(true);
+        sequential.scan();
+        for threads in [1, 3, 16] {
+            let mut parallel = SymlinkFollowFilter::new(Path::new("test_scan_parallel"));
+            parallel.keep_dir_links(true);
+            parallel.scan_parallel(threads);
+            assert_eq!(parallel.files(), sequential.files());
+        }
+        let mut sequential = SymlinkFilter::new(Path::new("test_scan_parallel"));
+        sequential.scan();
+        let mut parallel = SymlinkFilter::new(Path::new("test_scan_parallel"));
+        parallel.scan_parallel(4);
+        assert_eq!(parallel.files(), sequential.files());
+        assert_eq!(parallel.len(), 38);
+
+        fs::remove_dir_all("test_scan_parallel").unwrap();
+    }
+
+    #[test]
+    fn summary() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        assert_eq!(filter.summary(), None);
+        filter.scan();
+        let stats = filter.summary().unwrap();
+        assert_eq!(
+            (stats.files, stats.dirs, stats.symlinks, stats.other),
+            (3, 2, 3, 0)
+        );
+        assert_eq!(stats.total_size, 6);
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let stats = filter.summary().unwrap();
+        assert_eq!(
+            (stats.files, stats.dirs, stats.symlinks, stats.other),
+            (7, 3, 0, 0)
+        );
+        assert_eq!(stats.total_size, 24);
+    }
+
+    #[test]
+    fn dirs_only() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        assert!(filter.dirs_only().is_empty());
+        filter.scan();
+        let mut dirs: Vec<String> = filter.dirs_only().into_iter().map(|f| f.path).collect();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            ["resources/normalfolder", "resources/normalfolder/level1"]
+        );
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        assert_eq!(filter.dirs_only().len(), 3);
+    }
+
+    #[test]
+    fn update_keeping_cache() {
+        let mut fresh = SymlinkFilter::new(Path::new("resources/normalfolder/level1"));
+        fresh.scan();
+
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let filter = filter.update_keeping_cache(Path::new("resources/normalfolder/level1"));
+        // the result is available without scanning again
+        assert_eq!(filter.files(), fresh.files());
+        assert_eq!(filter.len(), 3);
+
+        let filter = filter.update_keeping_cache(Path::new("resources/normalsymlink"));
+        assert!(filter.files().is_none());
+
+        let mut filter = SymlinkFollowFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let filter = filter.update_keeping_cache(Path::new("resources/normalfolder/level1"));
+        assert!(filter.files().is_none());
+    }
+
+    #[test]
+    fn restrict_to() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        filter.restrict_to(&[Path::new("level1")]);
+        let mut paths: Vec<String> = filter.into_iter().map(|f| f.path).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "resources/normalfolder/level1",
+                "resources/normalfolder/level1/test1.ext1",
+                "resources/normalfolder/level1/test1.ext2",
+            ]
+        );
+    }
+
+    #[test]
+    fn with_contents_pairs() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let contents: Vec<_> = with_contents(filter.into_iter()).collect();
+        let (_, file) = contents
+            .iter()
+            .find(|(f, _)| f.path == "resources/normalfolder/level1/test1.ext1")
+            .unwrap();
+        assert_eq!(file.as_ref().unwrap(), b"123456");
+        let (_, link) = contents
+            .iter()
+            .find(|(f, _)| f.path == "resources/normalfolder/test")
+            .unwrap();
+        assert!(link.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn symlink_filter_checkpoint() {
+        let mut full = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        full.scan();
+
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        let checkpoint = filter.scan_until(3).expect("scan is unfinished");
+        assert_eq!(checkpoint.results.len(), 3);
+        checkpoint.save(Path::new("test_checkpoint.scan")).unwrap();
+        let mut checkpoint = ScanCheckpoint::load(Path::new("test_checkpoint.scan")).unwrap();
+        fs::remove_file("test_checkpoint.scan").unwrap();
+
+        // recorded values are kept even where the filesystem says otherwise
+        let mut recorded = checkpoint.clone();
+        recorded.results[0].size = 12345;
+        recorded.results[0].readonly = true;
+        recorded.results[0].uid = Some(4321);
+        recorded.results[0].gid = None;
+        recorded.results[0].abs_path = Some("/abs/path".to_owned());
+        recorded.results[0].flags = Some(2);
+        recorded.results[0].mount_point = Some("/".to_owned());
+        recorded
+            .save(Path::new("test_checkpoint_fields.scan"))
+            .unwrap();
+        let loaded = ScanCheckpoint::load(Path::new("test_checkpoint_fields.scan")).unwrap();
+        fs::remove_file("test_checkpoint_fields.scan").unwrap();
+        assert_eq!(loaded, recorded);
+
+        checkpoint
+            .queue
+            .push_front("resources/normalfolder/removed".to_owned());
+
+        filter.resume_scan(checkpoint);
+        assert_eq!(filter.files(), full.files());
+    }
+
+    #[test]
+    fn symlink_filter_to_tree() {
+        let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+        filter.scan();
+        let tree = filter.to_tree();
+        assert_eq!(
+            tree.info.as_ref().unwrap().path,
+            "resources/normalfolder".to_owned()
+        );
+        let level1 = &tree.children["level1"];
+        assert_eq!(level1.info.as_ref().unwrap().file_type, FileType::DIRECTORY);
+        assert_eq!(
+            level1.children.keys().collect::<Vec<_>>(),
+            vec!["test1.ext1", "test1.ext2"]
+        );
+        assert!(level1.children.values().all(|x| x.info.is_some()));
     }
 
     #[test]
@@ -1,12 +1,41 @@
+pub mod archive_log;
 pub mod args;
+pub mod cas;
 pub mod deflate;
+pub mod error;
 pub mod filter;
+pub mod limit;
+pub mod manifest;
+pub mod verify;
 
+pub use archive_log::ArchiveLog;
 pub use args::Args;
+pub use cas::CasDeflate;
+pub use deflate::plan;
+pub use deflate::ArchivePlan;
+pub use deflate::BaseMode;
+pub use deflate::CollisionPolicy;
+pub use deflate::CompressedPolicy;
 pub use deflate::Deflate;
+pub use deflate::FailurePolicy;
+pub use deflate::LinkPolicy;
+pub use deflate::PermissionPolicy;
+pub use deflate::PortabilityPolicy;
+pub use deflate::TextPolicy;
+pub use deflate::TraversalPolicy;
 pub use deflate::ZipDeflate;
+pub use deflate::ZipDeflateBuilder;
+pub use error::ClannadError;
 pub use filter::scan_symlink;
 pub use filter::scan_symlink_follow;
+pub use filter::with_contents;
+pub use filter::ArchiveFilter;
+pub use filter::DirWalk;
 pub use filter::Filter;
+pub use filter::ScanStats;
 pub use filter::SymlinkFilter;
 pub use filter::SymlinkFollowFilter;
+pub use limit::Limiter;
+pub use manifest::Manifest;
+pub use verify::verify_against;
+pub use verify::VerifyReport;
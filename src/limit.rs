@@ -0,0 +1,67 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Permits shared by filters and archives to cap how many files they keep open at once,
+/// for example across the archive jobs of a server.
+///
+/// A filter holds a permit while listing a directory, and `ZipDeflate` while writing
+/// each entry, so jobs sharing a limiter of `n` permits have at most `n` files open.
+///
+/// ```
+/// # use clannad::Limiter;
+/// # use std::sync::Arc;
+/// let limiter = Arc::new(Limiter::new(2));
+/// let permit = limiter.acquire();
+/// assert_eq!(limiter.available(), 1);
+/// drop(permit);
+/// assert_eq!(limiter.available(), 2);
+/// ```
+#[derive(Debug)]
+pub struct Limiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Limiter {
+    /// Limiter handing out at most `permits` permits at a time.
+    ///
+    /// # Panics
+    ///
+    /// If `permits` is 0, as nothing could ever be opened.
+    pub fn new(permits: usize) -> Self {
+        assert!(permits > 0, "a limiter needs at least one permit");
+        Self {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free permit, returned when the [`Permit`] is dropped.
+    pub fn acquire(self: &Arc<Self>) -> Permit {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit {
+            limiter: Arc::clone(self),
+        }
+    }
+
+    /// Number of permits not currently held.
+    pub fn available(&self) -> usize {
+        *self.available.lock().unwrap()
+    }
+}
+
+/// A permit of a [`Limiter`], held until dropped.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.limiter.available.lock().unwrap() += 1;
+        self.limiter.released.notify_one();
+    }
+}
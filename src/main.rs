@@ -1,8 +1,12 @@
 use clannad::args;
 use clannad::args::Parser;
 use clannad::Args;
+use std::process;
 
 fn main() {
     let args = Args::parse();
-    args::run(args);
+    if let Err(e) = args::run(args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
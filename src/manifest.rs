@@ -0,0 +1,88 @@
+use crate::filter::{FileInfo, FileType};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use zip::CompressionMethod;
+
+/// Files to archive, each with the name it is stored under and optionally its method.
+///
+/// Each line holds tab separated fields `source<TAB>stored_name[<TAB>method]`, the method
+/// being one of `stored`, `deflated`, `bzip2` and `zstd`. Entries without one use the
+/// method of the archive. Empty lines and lines starting with `#` are skipped. Relative
+/// sources are resolved in the working directory and read through symlinks.
+///
+/// ```
+/// # use clannad::{Deflate, Manifest, ZipDeflate};
+/// # use std::path::Path;
+/// let manifest = Manifest::parse(
+///     "resources/normalfolder/test1\tnotes.txt\tstored\n\
+///      resources/normalfolder/level1/test1.ext1\tdeep/ext1.txt\n",
+/// )
+/// .unwrap();
+/// let mut deflate =
+///     ZipDeflate::new(Path::new("doc_manifest.zip")).with_entry_methods(manifest.methods);
+/// deflate.write_archive(&manifest.files);
+/// deflate.finish().unwrap();
+/// # std::fs::remove_file("doc_manifest.zip").unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    // entries read from their source, with the stored name as path
+    pub files: Vec<FileInfo>,
+    // methods by stored name, for `ZipDeflate::with_entry_methods`
+    pub methods: HashMap<String, CompressionMethod>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut manifest = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("manifest line {}: {}", number + 1, message),
+                )
+            };
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (source, name, method) = match fields[..] {
+                [source, name] => (source, name, None),
+                [source, name, method] => (source, name, Some(method)),
+                _ => return Err(invalid("expected a source, a stored name and a method")),
+            };
+            if source.is_empty() || name.is_empty() {
+                return Err(invalid("empty path"));
+            }
+            if let Some(method) = method {
+                let method = parse_method(method).ok_or_else(|| invalid("unknown method"))?;
+                manifest.methods.insert(name.to_owned(), method);
+            }
+            let file_type =
+                fs::metadata(source).map_or(FileType::NONE, |m| FileType::from_metadata(&m, false));
+            manifest.files.push(FileInfo::new(
+                Path::new(name),
+                Path::new(source),
+                file_type,
+                None,
+            ));
+        }
+        Ok(manifest)
+    }
+}
+
+fn parse_method(name: &str) -> Option<CompressionMethod> {
+    match name {
+        "stored" => Some(CompressionMethod::Stored),
+        "deflated" => Some(CompressionMethod::Deflated),
+        "bzip2" => Some(CompressionMethod::Bzip2),
+        "zstd" => Some(CompressionMethod::Zstd),
+        _ => None,
+    }
+}
@@ -0,0 +1,115 @@
+use crate::deflate::{dir_entry_name, sanitize_entry_name, slashed_name, TraversalPolicy};
+use crate::filter::{FileInfo, FileType};
+use crate::{ClannadError, Filter, SymlinkFilter, SymlinkFollowFilter};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Differences between an archive and the tree it was made from, by stored name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    // entries of the tree absent from the archive
+    pub missing: Vec<String>,
+    // entries of the archive absent from the tree
+    pub extra: Vec<String>,
+    // files whose size or content, or symlinks whose target, differ
+    pub mismatched: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Whether the archive represents the tree faithfully.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Check that `archive`, made from `src` with the default `ZipDeflate` options, holds every
+/// entry of `src` with the same content and nothing else.
+///
+/// Files are compared by size and hash, symlinks by the hash of their target path and
+/// directories by presence only. Fails if `archive` cannot be opened as a zip archive.
+pub fn verify_against(
+    archive: &Path,
+    src: &Path,
+    follow_symlinks: bool,
+) -> Result<VerifyReport, ClannadError> {
+    let mut archive = ZipArchive::new(File::open(archive)?)?;
+    let files = match follow_symlinks {
+        true => scan::<SymlinkFollowFilter>(src),
+        false => scan::<SymlinkFilter>(src),
+    };
+    let mut report = VerifyReport::default();
+    let mut expected = HashSet::new();
+    files.iter().for_each(|f| {
+        let name = match stored_name(f) {
+            Some(name) => name,
+            None => return,
+        };
+        if !expected.insert(name.clone()) {
+            return;
+        }
+        let mut entry = match archive.by_name(&name) {
+            Ok(entry) => entry,
+            Err(_) => {
+                report.missing.push(name);
+                return;
+            }
+        };
+        let source = match (&f.symlink_path, f.file_type) {
+            // stored as the link itself, its content is the target path
+            (Some(target), _) => content_digest(&mut target.as_bytes()),
+            (None, FileType::REGULAR) => {
+                File::open(&f.content_path).and_then(|mut file| content_digest(&mut file))
+            }
+            _ => return,
+        };
+        let stored = content_digest(&mut entry);
+        match (stored, source) {
+            (Ok(stored), Ok(source)) if stored == source => {}
+            _ => report.mismatched.push(name),
+        }
+    });
+    report.extra = archive
+        .file_names()
+        .filter(|name| !expected.contains(*name))
+        .map(|name| name.to_owned())
+        .collect();
+    Ok(report)
+}
+
+fn scan<F: Filter>(src: &Path) -> Vec<FileInfo> {
+    let mut filter = F::new(src);
+    filter.scan();
+    filter.into_iter().collect()
+}
+
+/// Name `write_archive` stores `f` under, `None` for entries it leaves out.
+fn stored_name(f: &FileInfo) -> Option<String> {
+    let name =
+        sanitize_entry_name(&slashed_name(Path::new(&f.path)), TraversalPolicy::Strip).ok()?;
+    let dir_name = dir_entry_name(Path::new(&name));
+    match (&f.symlink_path, f.file_type) {
+        (None, FileType::DIRECTORY) => Some(dir_name),
+        (None, FileType::REGULAR) | (Some(_), _) => Some(dir_name.trim_end_matches('/').to_owned()),
+        _ => None,
+    }
+}
+
+/// Size and hash of everything `reader` yields.
+fn content_digest(reader: &mut dyn Read) -> io::Result<(u64, u64)> {
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((size, hasher.finish()));
+        }
+        hasher.write(&buffer[..read]);
+        size += read as u64;
+    }
+}
@@ -0,0 +1,171 @@
+use clannad::args::{self, Parser};
+use clannad::{Args, ClannadError};
+use std::fs::{create_dir_all, remove_dir_all, remove_file, write, File};
+use zip::ZipArchive;
+
+#[test]
+fn exclude_cli() {
+    create_dir_all("test_exclude_cli/logs").unwrap();
+    write("test_exclude_cli/keep.txt", "keep").unwrap();
+    write("test_exclude_cli/drop.log", "drop").unwrap();
+    write("test_exclude_cli/logs/old.log", "drop").unwrap();
+    write("test_exclude_cli/drop.tmp", "drop").unwrap();
+    write("test_exclude_cli.excludes", "# temporaries\n*.tmp\n").unwrap();
+
+    args::run(Args::parse_from([
+        "clannad",
+        "test_exclude_cli.zip",
+        "--exclude",
+        "*.log",
+        "--exclude-from",
+        "test_exclude_cli.excludes",
+        "test_exclude_cli",
+    ]))
+    .unwrap();
+
+    let archive = ZipArchive::new(File::open("test_exclude_cli.zip").unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert!(names.contains(&"test_exclude_cli/keep.txt"));
+    assert!(names.contains(&"test_exclude_cli/logs/"));
+    assert!(!names
+        .iter()
+        .any(|n| n.ends_with(".log") || n.ends_with(".tmp")));
+
+    remove_file("test_exclude_cli.zip").unwrap();
+    remove_file("test_exclude_cli.excludes").unwrap();
+    remove_dir_all("test_exclude_cli").unwrap();
+}
+
+#[test]
+fn from_file0_cli() {
+    create_dir_all("test_from_file0_cli").unwrap();
+    write("test_from_file0_cli/new\nline.txt", "123456").unwrap();
+    write("test_from_file0_cli/plain.txt", "123456").unwrap();
+    write(
+        "test_from_file0_cli.list",
+        "test_from_file0_cli/new\nline.txt\0test_from_file0_cli/plain.txt\0",
+    )
+    .unwrap();
+
+    args::run(Args::parse_from([
+        "clannad",
+        "test_from_file0_cli.zip",
+        "--from-file0",
+        "test_from_file0_cli.list",
+    ]))
+    .unwrap();
+
+    // the name with a newline is read whole from the list, then rejected for its
+    // control character instead of being split into two missing files
+    let archive = ZipArchive::new(File::open("test_from_file0_cli.zip").unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert_eq!(names, vec!["test_from_file0_cli/plain.txt"]);
+
+    remove_file("test_from_file0_cli.zip").unwrap();
+    remove_file("test_from_file0_cli.list").unwrap();
+    remove_dir_all("test_from_file0_cli").unwrap();
+}
+
+#[test]
+fn base_dir_cli() {
+    args::run(Args::parse_from([
+        "clannad",
+        "test_base_dir_cli.zip",
+        "-C",
+        "resources",
+        "normalfolder",
+    ]))
+    .unwrap();
+
+    let archive = ZipArchive::new(File::open("test_base_dir_cli.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "normalfolder/",
+            "normalfolder/current",
+            "normalfolder/curtest",
+            "normalfolder/level1/",
+            "normalfolder/level1/test1.ext1",
+            "normalfolder/level1/test1.ext2",
+            "normalfolder/test",
+            "normalfolder/test1",
+        ]
+    );
+
+    remove_file("test_base_dir_cli.zip").unwrap();
+}
+
+#[test]
+fn expand_cli() {
+    create_dir_all("test_expand_cli").unwrap();
+    write("test_expand_cli/a.txt", "a").unwrap();
+    std::env::set_var("CLANNAD_TEST_EXPAND", "test_expand_cli");
+
+    args::run(Args::parse_from([
+        "clannad",
+        "test_expand_cli.zip",
+        "${CLANNAD_TEST_EXPAND}/a.txt",
+    ]))
+    .unwrap();
+
+    let archive = ZipArchive::new(File::open("test_expand_cli.zip").unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert_eq!(names, ["test_expand_cli/a.txt"]);
+
+    remove_file("test_expand_cli.zip").unwrap();
+    remove_dir_all("test_expand_cli").unwrap();
+}
+
+#[test]
+fn invalid_root_cli() {
+    let result = args::run(Args::parse_from([
+        "clannad",
+        "test_invalid_root_cli.zip",
+        "test_invalid_root_cli",
+    ]));
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "test_invalid_root_cli is not a valid root: root does not exist"
+    );
+    assert!(!std::path::Path::new("test_invalid_root_cli.zip").exists());
+}
+
+#[test]
+fn missing_exclude_from_cli() {
+    let result = args::run(Args::parse_from([
+        "clannad",
+        "test_missing_exclude_from_cli.zip",
+        "--exclude-from",
+        "test_missing_exclude_from_cli.excludes",
+        "resources/normalfolder",
+    ]));
+
+    assert!(matches!(result, Err(ClannadError::Io(_))));
+    assert!(!std::path::Path::new("test_missing_exclude_from_cli.zip").exists());
+}
+
+#[test]
+fn invalid_from_file0_cli() {
+    let result = args::run(Args::parse_from([
+        "clannad",
+        "test_invalid_from_file0_cli.zip",
+        "--from-file0",
+        "test_invalid_from_file0_cli.list",
+    ]));
+    assert!(matches!(result, Err(ClannadError::Io(_))));
+
+    write("test_invalid_from_file0_cli.list", b"resources\0bad\xff\0").unwrap();
+    let result = args::run(Args::parse_from([
+        "clannad",
+        "test_invalid_from_file0_cli.zip",
+        "--from-file0",
+        "test_invalid_from_file0_cli.list",
+    ]));
+    assert!(matches!(result, Err(ClannadError::InvalidPath(_))));
+    assert!(!std::path::Path::new("test_invalid_from_file0_cli.zip").exists());
+
+    remove_file("test_invalid_from_file0_cli.list").unwrap();
+}
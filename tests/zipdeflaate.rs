@@ -1,10 +1,25 @@
-use clannad::{Deflate, Filter, SymlinkFilter, ZipDeflate};
+use clannad::cas::{CasDeflate, CAS_MANIFEST};
+use clannad::deflate::{
+    archive_incremental, check_windows_name, find_collisions, level_range, pipeline, read_metadata,
+    sanitize_entry_name, INDEX_ENTRY_NAME,
+};
+use clannad::filter::{ArchiveFilter, BasicFilter, FileInfo, FileType, SymlinkPolicy};
+use clannad::{
+    plan, verify_against, ArchiveLog, BaseMode, ClannadError, CollisionPolicy, CompressedPolicy,
+    Deflate, FailurePolicy, Filter, Limiter, LinkPolicy, Manifest, PermissionPolicy,
+    PortabilityPolicy, SymlinkFilter, TextPolicy, TraversalPolicy, ZipDeflate, ZipDeflateBuilder,
+};
 use std::{
-    fs::{remove_file, File},
-    io::Read,
+    collections::HashMap,
+    fs::{create_dir_all, remove_dir_all, remove_file, set_permissions, write, File, OpenOptions},
+    io::{Cursor, Read, Write},
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    thread,
 };
-use zip::ZipArchive;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
 
 #[test]
 fn basic_deflate() {
@@ -25,3 +40,2357 @@ fn basic_deflate() {
 
     remove_file("test.zip").unwrap();
 }
+
+#[test]
+fn extension_methods_deflate() {
+    create_dir_all("test_extension_methods").unwrap();
+    write("test_extension_methods/a.txt", "aaaaaaaaaaaaaaaa").unwrap();
+    write("test_extension_methods/b.bin", "bbbbbbbbbbbbbbbb").unwrap();
+    let files: Vec<FileInfo> = [
+        "test_extension_methods/a.txt",
+        "test_extension_methods/b.bin",
+    ]
+    .iter()
+    .map(|p| FileInfo::new(Path::new(p), Path::new(p), FileType::REGULAR, None))
+    .collect();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_extension_methods.zip"))
+        .with_method(CompressionMethod::Deflated)
+        .with_extension_methods(HashMap::from([
+            ("txt".to_owned(), CompressionMethod::Deflated),
+            ("bin".to_owned(), CompressionMethod::Stored),
+        ]));
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_extension_methods.zip").unwrap()).unwrap();
+    assert_eq!(
+        archive
+            .by_name("test_extension_methods/a.txt")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Deflated
+    );
+    assert_eq!(
+        archive
+            .by_name("test_extension_methods/b.bin")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Stored
+    );
+
+    remove_file("test_extension_methods.zip").unwrap();
+    remove_dir_all("test_extension_methods").unwrap();
+}
+
+#[test]
+fn collision_detect() {
+    let files = vec![
+        FileInfo::new(
+            Path::new("level1/test1.ext1"),
+            Path::new("resources/normalfolder/level1/test1.ext1"),
+            FileType::REGULAR,
+            None,
+        ),
+        FileInfo::new(
+            Path::new("level1/test1.ext1"),
+            Path::new("resources/normalfolder/level1/test1.ext2"),
+            FileType::REGULAR,
+            None,
+        ),
+        FileInfo::new(
+            Path::new("test1"),
+            Path::new("resources/normalfolder/test1"),
+            FileType::REGULAR,
+            None,
+        ),
+    ];
+    let collisions = find_collisions(&files);
+    assert_eq!(collisions.len(), 1);
+    assert_eq!(collisions[0].name, "level1/test1.ext1");
+    assert_eq!(
+        collisions[0].sources,
+        vec![
+            "resources/normalfolder/level1/test1.ext1",
+            "resources/normalfolder/level1/test1.ext2"
+        ]
+    );
+
+    let mut deflate = ZipDeflate::new(Path::new("test_collision_detect.zip"));
+    deflate.write_archive(&files);
+    assert_eq!(
+        deflate.warnings(),
+        [
+            "level1/test1.ext1 is stored from resources/normalfolder/level1/test1.ext1, \
+             resources/normalfolder/level1/test1.ext2",
+            "level1/test1.ext1 is stored already, skipped"
+        ]
+    );
+    deflate.finish().unwrap();
+    remove_file("test_collision_detect.zip").unwrap();
+}
+
+#[test]
+fn dir_trailing_slash_deflate() {
+    let mut deflate = ZipDeflate::new(Path::new("test_trailing_slash.zip"));
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().expect("dir is valid"));
+    deflate.write_dir(Path::new("extra//dir/"));
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_trailing_slash.zip").unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert!(names.contains(&"resources/normalfolder/level1/"));
+    assert!(names.contains(&"extra/dir/"));
+    assert!(!names.iter().any(|n| n.ends_with("//")));
+
+    remove_file("test_trailing_slash.zip").unwrap();
+}
+
+#[test]
+fn prefix_deflate() {
+    let mut deflate = ZipDeflate::new(Path::new("test_prefix.zip")).with_prefix("bundle");
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().expect("dir is valid"));
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_prefix.zip").unwrap()).unwrap();
+    assert!(archive.file_names().all(|n| n.starts_with("bundle/")));
+    let mut content = String::new();
+    archive
+        .by_name("bundle/resources/normalfolder/level1/test1.ext1")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, String::from("123456"));
+
+    remove_file("test_prefix.zip").unwrap();
+}
+
+#[test]
+fn size_change_deflate() {
+    create_dir_all("test_size_change").unwrap();
+    write("test_size_change/grow.txt", "123").unwrap();
+    let files = vec![FileInfo::new(
+        Path::new("test_size_change/grow.txt"),
+        Path::new("test_size_change/grow.txt"),
+        FileType::REGULAR,
+        None,
+    )];
+    assert_eq!(files[0].size, 3);
+    OpenOptions::new()
+        .append(true)
+        .open("test_size_change/grow.txt")
+        .unwrap()
+        .write_all(b"456")
+        .unwrap();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_size_change.zip"));
+    deflate.write_archive(&files);
+    assert_eq!(
+        deflate.warnings(),
+        ["test_size_change/grow.txt changed size while reading, 6 of 3 bytes stored"]
+    );
+    let written =
+        deflate.write_file_streaming(Path::new("shrunk.txt"), &mut Cursor::new(b"12".to_vec()));
+    assert_eq!(written, 2);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_size_change.zip").unwrap()).unwrap();
+    let mut content = String::new();
+    let mut entry = archive.by_name("test_size_change/grow.txt").unwrap();
+    assert_eq!(entry.size(), 6);
+    entry.read_to_string(&mut content).unwrap();
+    assert_eq!(content, String::from("123456"));
+    drop(entry);
+    assert_eq!(archive.by_name("shrunk.txt").unwrap().size(), 2);
+
+    remove_file("test_size_change.zip").unwrap();
+    remove_dir_all("test_size_change").unwrap();
+}
+
+#[test]
+fn base_mode_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let files = filter.files().as_ref().expect("dir is valid");
+
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_include_root.zip")).with_base(BaseMode::IncludeRoot);
+    deflate.write_archive(files);
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_include_root.zip").unwrap()).unwrap();
+    assert!(archive
+        .file_names()
+        .all(|n| n.starts_with("resources/normalfolder")));
+    assert!(archive
+        .file_names()
+        .any(|n| n == "resources/normalfolder/level1/test1.ext1"));
+
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_exclude_root.zip")).with_base(BaseMode::ExcludeRoot);
+    deflate.write_archive(files);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_exclude_root.zip").unwrap()).unwrap();
+    assert!(archive.file_names().all(|n| !n.starts_with("resources")));
+    let mut content = String::new();
+    archive
+        .by_name("level1/test1.ext1")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, String::from("123456"));
+
+    let mut deflate = ZipDeflate::new(Path::new("test_exclude_root_prefix.zip"))
+        .with_base(BaseMode::ExcludeRoot)
+        .with_prefix("bundle");
+    deflate.write_archive(files);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_exclude_root_prefix.zip").unwrap()).unwrap();
+    assert!(archive.by_name("bundle/level1/test1.ext1").is_ok());
+
+    remove_file("test_include_root.zip").unwrap();
+    remove_file("test_exclude_root.zip").unwrap();
+    remove_file("test_exclude_root_prefix.zip").unwrap();
+}
+
+#[test]
+fn base_mode_single_file_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder/level1/test1.ext1"));
+    filter.scan();
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_exclude_root_file.zip")).with_base(BaseMode::ExcludeRoot);
+    deflate.write_archive(filter.files().as_ref().expect("file is valid"));
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_exclude_root_file.zip").unwrap()).unwrap();
+    assert_eq!(archive.file_names().collect::<Vec<_>>(), vec!["test1.ext1"]);
+
+    remove_file("test_exclude_root_file.zip").unwrap();
+}
+
+#[test]
+fn base_mode_basename_deflate() {
+    let root = std::fs::canonicalize("resources/normalfolder/level1").unwrap();
+    let mut filter = SymlinkFilter::new(&root);
+    filter.scan();
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_basename_root.zip")).with_base(BaseMode::Basename);
+    deflate.write_archive(filter.files().as_ref().expect("dir is valid"));
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_basename_root.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["level1/", "level1/test1.ext1", "level1/test1.ext2"]);
+
+    let mut filter = SymlinkFilter::new(&root.join("test1.ext1"));
+    filter.scan();
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_basename_file.zip")).with_base(BaseMode::Basename);
+    deflate.write_archive(filter.files().as_ref().expect("file is valid"));
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_basename_file.zip").unwrap()).unwrap();
+    assert_eq!(archive.file_names().collect::<Vec<_>>(), vec!["test1.ext1"]);
+
+    remove_file("test_basename_root.zip").unwrap();
+    remove_file("test_basename_file.zip").unwrap();
+}
+
+#[test]
+fn readonly_deflate() {
+    create_dir_all("test_readonly").unwrap();
+    write("test_readonly/locked.txt", "locked").unwrap();
+    write("test_readonly/open.txt", "open").unwrap();
+    let mut permissions = File::open("test_readonly/locked.txt")
+        .unwrap()
+        .metadata()
+        .unwrap()
+        .permissions();
+    permissions.set_readonly(true);
+    set_permissions("test_readonly/locked.txt", permissions).unwrap();
+
+    let mut filter = SymlinkFilter::new(Path::new("test_readonly"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_readonly.zip")).with_readonly(true);
+    deflate.write_archive(filter.files().as_ref().expect("dir is valid"));
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_readonly.zip").unwrap()).unwrap();
+    let locked = archive
+        .by_name("test_readonly/locked.txt")
+        .unwrap()
+        .unix_mode()
+        .unwrap();
+    assert_eq!(locked & 0o777, 0o444);
+    let open = archive
+        .by_name("test_readonly/open.txt")
+        .unwrap()
+        .unix_mode();
+    assert_ne!(open.map(|mode| mode & 0o200), Some(0));
+
+    remove_file("test_readonly.zip").unwrap();
+    remove_dir_all("test_readonly").unwrap();
+}
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mmap_deflate() {
+    let content: Vec<u8> = (0..(3 << 20)).map(|i| (i % 251) as u8).collect();
+    write("test_mmap.bin", &content).unwrap();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_mmap.zip"));
+    deflate.write_file_mapped(
+        Path::new("mapped.bin"),
+        &mut File::open("test_mmap.bin").unwrap(),
+    );
+    deflate.write_file_streaming(
+        Path::new("buffered.bin"),
+        &mut File::open("test_mmap.bin").unwrap(),
+    );
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_mmap.zip").unwrap()).unwrap();
+    let mut mapped = Vec::new();
+    archive
+        .by_name("mapped.bin")
+        .unwrap()
+        .read_to_end(&mut mapped)
+        .unwrap();
+    let mut buffered = Vec::new();
+    archive
+        .by_name("buffered.bin")
+        .unwrap()
+        .read_to_end(&mut buffered)
+        .unwrap();
+    assert_eq!(mapped, content);
+    assert_eq!(mapped, buffered);
+    let mapped_crc = archive.by_name("mapped.bin").unwrap().crc32();
+    assert_eq!(mapped_crc, archive.by_name("buffered.bin").unwrap().crc32());
+
+    remove_file("test_mmap.zip").unwrap();
+    remove_file("test_mmap.bin").unwrap();
+}
+
+fn with_unreadable_file() -> Vec<FileInfo> {
+    vec![
+        FileInfo::new(
+            Path::new("resources/normalfolder/test1"),
+            Path::new("resources/normalfolder/test1"),
+            FileType::REGULAR,
+            None,
+        ),
+        FileInfo::new(
+            Path::new("resources/normalfolder/missing"),
+            Path::new("resources/normalfolder/missing"),
+            FileType::REGULAR,
+            None,
+        ),
+        FileInfo::new(
+            Path::new("resources/normalfolder/level1/test1.ext1"),
+            Path::new("resources/normalfolder/level1/test1.ext1"),
+            FileType::REGULAR,
+            None,
+        ),
+    ]
+}
+
+#[test]
+fn failure_policy_abort_deflate() {
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_abort.zip")).with_failure_policy(FailurePolicy::Abort);
+    deflate.write_archive(&with_unreadable_file());
+    let error = deflate.finish().unwrap_err();
+    assert!(matches!(error, ClannadError::Skipped(_)));
+    assert!(error.to_string().contains("resources/normalfolder/missing"));
+    assert!(!Path::new("test_abort.zip").exists());
+}
+
+#[test]
+fn failure_policy_skip_deflate() {
+    let mut deflate = ZipDeflate::new(Path::new("test_skip.zip"))
+        .with_failure_policy(FailurePolicy::SkipWithWarning);
+    deflate.write_archive(&with_unreadable_file());
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_skip.zip").unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&"resources/normalfolder/level1/test1.ext1"));
+
+    remove_file("test_skip.zip").unwrap();
+}
+
+#[test]
+fn failed_entry_deflate() {
+    struct Failing(usize);
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0 {
+                0 => Err(std::io::Error::other("unreadable")),
+                _ => {
+                    self.0 -= 1;
+                    buf[0] = b'x';
+                    Ok(1)
+                }
+            }
+        }
+    }
+
+    let mut deflate = ZipDeflate::new(Path::new("test_failed_entry.zip"));
+    deflate.write_file(Path::new("first.txt"), b"first");
+    // the name is taken, so the content must not end up in first.txt
+    deflate.write_file(Path::new("first.txt"), b"second");
+    assert_eq!(
+        deflate.write_file_streaming(Path::new("broken.txt"), &mut Failing(3)),
+        0
+    );
+    deflate.write_file(Path::new("last.txt"), b"last");
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_failed_entry.zip").unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["first.txt", "last.txt"]);
+    let mut content = String::new();
+    archive
+        .by_name("first.txt")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "first");
+
+    remove_file("test_failed_entry.zip").unwrap();
+}
+
+#[test]
+fn traversal_deflate() {
+    let files = vec![FileInfo::new(
+        Path::new("../evil"),
+        Path::new("resources/normalfolder/level1/test1.ext1"),
+        FileType::REGULAR,
+        None,
+    )];
+
+    let mut deflate = ZipDeflate::new(Path::new("test_traversal_strip.zip"))
+        .with_traversal(TraversalPolicy::Strip);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_traversal_strip.zip").unwrap()).unwrap();
+    assert_eq!(archive.file_names().collect::<Vec<_>>(), vec!["evil"]);
+
+    let mut deflate = ZipDeflate::new(Path::new("test_traversal_reject.zip"))
+        .with_traversal(TraversalPolicy::Reject)
+        .with_failure_policy(FailurePolicy::Abort);
+    deflate.write_archive(&files);
+    let error = deflate.finish().unwrap_err();
+    assert!(error.to_string().contains("escapes the archive root"));
+
+    remove_file("test_traversal_strip.zip").unwrap();
+}
+
+#[test]
+fn copy_entry_deflate() {
+    let mut deflate = ZipDeflate::new(Path::new("test_copy_entry.zip"));
+    deflate.write_file(Path::new("a.txt"), b"123456");
+    deflate.copy_entry("a.txt", "b.txt").unwrap();
+    let error = deflate.copy_entry("missing.txt", "c.txt").unwrap_err();
+    assert!(matches!(error, ClannadError::InvalidPath(_)));
+    assert!(error.to_string().contains("missing.txt"));
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_copy_entry.zip").unwrap()).unwrap();
+    for name in ["a.txt", "b.txt"] {
+        let mut content = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, String::from("123456"));
+    }
+    assert!(archive.by_name("c.txt").is_err());
+
+    remove_file("test_copy_entry.zip").unwrap();
+}
+
+#[test]
+fn builder_deflate() {
+    write("test_builder.txt", "123456").unwrap();
+    let mut deflate = ZipDeflateBuilder::new()
+        .method(CompressionMethod::Stored)
+        .prefix("release")
+        .build(Path::new("test_builder.zip"))
+        .unwrap();
+    deflate.write_archive(&vec![FileInfo::new(
+        Path::new("test_builder.txt"),
+        Path::new("test_builder.txt"),
+        FileType::REGULAR,
+        None,
+    )]);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_builder.zip").unwrap()).unwrap();
+    assert_eq!(
+        archive
+            .by_name("release/test_builder.txt")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Stored
+    );
+
+    let mut deflate =
+        ZipDeflateBuilder::new().build_writer(File::create("test_builder_writer.zip").unwrap());
+    deflate.write_file(Path::new("a.txt"), b"123456");
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_builder_writer.zip").unwrap()).unwrap();
+    assert_eq!(archive.file_names().collect::<Vec<_>>(), vec!["a.txt"]);
+
+    remove_file("test_builder.txt").unwrap();
+    remove_file("test_builder.zip").unwrap();
+    remove_file("test_builder_writer.zip").unwrap();
+}
+
+#[test]
+fn write_archive_iter_deflate() {
+    create_dir_all("test_archive_iter").unwrap();
+    let names = ["a.txt", "b.txt", "c.txt"];
+    let lazy = names.iter().map(|name| {
+        let path = format!("test_archive_iter/{}", name);
+        write(&path, name).unwrap();
+        FileInfo::new(Path::new(&path), Path::new(&path), FileType::REGULAR, None)
+    });
+    let mut deflate = ZipDeflate::new(Path::new("test_archive_iter.zip"));
+    deflate.write_archive_iter(lazy);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_archive_iter.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 3);
+    for name in names {
+        let mut content = String::new();
+        archive
+            .by_name(&format!("test_archive_iter/{}", name))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, name);
+    }
+
+    remove_file("test_archive_iter.zip").unwrap();
+    remove_dir_all("test_archive_iter").unwrap();
+}
+
+#[test]
+fn mtime_fn_deflate() {
+    let date = DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_mtime_fn.zip")).with_mtime_fn(Box::new(move |_| date));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_mtime_fn.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 8);
+    for i in 0..archive.len() {
+        assert_eq!(archive.by_index(i).unwrap().last_modified(), Some(date));
+    }
+
+    remove_file("test_mtime_fn.zip").unwrap();
+}
+
+#[test]
+fn pipeline_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_pipeline_two_phase.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_pipeline.zip"));
+    pipeline(
+        Path::new("resources/normalfolder"),
+        SymlinkPolicy::Retain,
+        &[],
+        &mut deflate,
+        2,
+    );
+    deflate.finish().unwrap();
+
+    let mut two_phase =
+        ZipArchive::new(File::open("test_pipeline_two_phase.zip").unwrap()).unwrap();
+    let mut pipelined = ZipArchive::new(File::open("test_pipeline.zip").unwrap()).unwrap();
+    assert_eq!(pipelined.len(), 8);
+    assert_eq!(
+        pipelined.file_names().collect::<Vec<_>>(),
+        two_phase.file_names().collect::<Vec<_>>()
+    );
+    for i in 0..two_phase.len() {
+        let mut expected = Vec::new();
+        two_phase
+            .by_index(i)
+            .unwrap()
+            .read_to_end(&mut expected)
+            .unwrap();
+        let mut content = Vec::new();
+        pipelined
+            .by_index(i)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, expected);
+    }
+
+    remove_file("test_pipeline_two_phase.zip").unwrap();
+    remove_file("test_pipeline.zip").unwrap();
+}
+
+#[test]
+fn compressed_policy_deflate() {
+    create_dir_all("test_compressed_policy").unwrap();
+    let mut gzip = vec![0x1f, 0x8b, 0x08];
+    gzip.extend([0; 1024]);
+    write("test_compressed_policy/data.bin", &gzip).unwrap();
+    write("test_compressed_policy/text.txt", [b'a'; 1024]).unwrap();
+    let files = vec![
+        FileInfo::new(
+            Path::new("test_compressed_policy/data.bin"),
+            Path::new("test_compressed_policy/data.bin"),
+            FileType::REGULAR,
+            None,
+        ),
+        FileInfo::new(
+            Path::new("test_compressed_policy/text.txt"),
+            Path::new("test_compressed_policy/text.txt"),
+            FileType::REGULAR,
+            None,
+        ),
+    ];
+
+    let mut deflate = ZipDeflate::new(Path::new("test_compressed_policy.zip"))
+        .with_compressed_policy(CompressedPolicy::Store);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_compressed_policy.zip").unwrap()).unwrap();
+    let mut content = Vec::new();
+    let mut stored = archive.by_name("test_compressed_policy/data.bin").unwrap();
+    assert_eq!(stored.compression(), CompressionMethod::Stored);
+    stored.read_to_end(&mut content).unwrap();
+    assert_eq!(content, gzip);
+    drop(stored);
+    assert_eq!(
+        archive
+            .by_name("test_compressed_policy/text.txt")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Deflated
+    );
+
+    let mut deflate = ZipDeflate::new(Path::new("test_compressed_policy.zip"))
+        .with_compressed_policy(CompressedPolicy::Skip);
+    deflate.write_archive(&files);
+    assert_eq!(
+        deflate.warnings(),
+        ["test_compressed_policy/data.bin is already compressed, skipped"]
+    );
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_compressed_policy.zip").unwrap()).unwrap();
+    assert_eq!(
+        archive.file_names().collect::<Vec<_>>(),
+        vec!["test_compressed_policy/text.txt"]
+    );
+
+    remove_file("test_compressed_policy.zip").unwrap();
+    remove_dir_all("test_compressed_policy").unwrap();
+}
+
+#[test]
+fn buffer_size_deflate() {
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    write("test_buffer_size.bin", &content).unwrap();
+    let files = vec![FileInfo::new(
+        Path::new("test_buffer_size.bin"),
+        Path::new("test_buffer_size.bin"),
+        FileType::REGULAR,
+        None,
+    )];
+    let date = DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap();
+
+    let mut archives = Vec::new();
+    for size in [1, 1 << 20] {
+        let mut deflate = ZipDeflate::new(Path::new("test_buffer_size.zip"))
+            .with_buffer_size(size)
+            .with_mtime_fn(Box::new(move |_| date));
+        deflate.write_archive(&files);
+        deflate.finish().unwrap();
+        archives.push(std::fs::read("test_buffer_size.zip").unwrap());
+    }
+    assert_eq!(archives[0], archives[1]);
+
+    let mut archive = ZipArchive::new(Cursor::new(&archives[0])).unwrap();
+    let mut stored = Vec::new();
+    archive
+        .by_name("test_buffer_size.bin")
+        .unwrap()
+        .read_to_end(&mut stored)
+        .unwrap();
+    assert_eq!(stored, content);
+
+    remove_file("test_buffer_size.bin").unwrap();
+    remove_file("test_buffer_size.zip").unwrap();
+}
+
+#[test]
+fn overlapping_roots_deflate() {
+    create_dir_all("test_overlapping_roots/sub").unwrap();
+    write("test_overlapping_roots/sub/a.txt", "123456").unwrap();
+    let mut files = Vec::new();
+    for root in ["test_overlapping_roots", "test_overlapping_roots/sub"] {
+        let mut filter = SymlinkFilter::new(Path::new(root));
+        filter.scan();
+        files.extend(filter);
+    }
+    let mut deflate = ZipDeflate::new(Path::new("test_overlapping_roots.zip"))
+        .with_failure_policy(FailurePolicy::Abort);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_overlapping_roots.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "test_overlapping_roots/",
+            "test_overlapping_roots/sub/",
+            "test_overlapping_roots/sub/a.txt"
+        ]
+    );
+
+    remove_file("test_overlapping_roots.zip").unwrap();
+    remove_dir_all("test_overlapping_roots").unwrap();
+}
+
+#[cfg(feature = "log")]
+struct CaptureLogger(std::sync::Mutex<Vec<String>>);
+
+#[cfg(feature = "log")]
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "log")]
+static LOGGER: CaptureLogger = CaptureLogger(std::sync::Mutex::new(Vec::new()));
+
+#[cfg(feature = "log")]
+#[test]
+fn decision_log_deflate() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    create_dir_all("test_decision_log").unwrap();
+    let mut gzip = vec![0x1f, 0x8b, 0x08];
+    gzip.extend([0; 1024]);
+    write("test_decision_log/data.bin", &gzip).unwrap();
+    write("test_decision_log/text.txt", [b'a'; 1024]).unwrap();
+    let files: Vec<FileInfo> = ["test_decision_log/data.bin", "test_decision_log/text.txt"]
+        .iter()
+        .map(|p| FileInfo::new(Path::new(p), Path::new(p), FileType::REGULAR, None))
+        .collect();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_decision_log.zip"))
+        .with_compressed_policy(CompressedPolicy::Store);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let logs = LOGGER.0.lock().unwrap();
+    assert!(logs.contains(
+        &"test_decision_log/data.bin: extension Some(\"bin\"), already compressed true, Stored chosen"
+            .to_owned()
+    ));
+    assert!(logs.contains(
+        &"test_decision_log/text.txt: extension Some(\"txt\"), already compressed false, default method chosen"
+            .to_owned()
+    ));
+    drop(logs);
+
+    remove_file("test_decision_log.zip").unwrap();
+    remove_dir_all("test_decision_log").unwrap();
+}
+
+#[test]
+fn from_zip_writer_deflate() {
+    let mut writer = ZipWriter::new(File::create("test_from_zip_writer.zip").unwrap());
+    writer.set_comment("embedded");
+    writer
+        .start_file("manual.txt", SimpleFileOptions::default())
+        .unwrap();
+    writer.write_all(b"manual").unwrap();
+
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::from_zip_writer(writer);
+    deflate.write_archive(&filter.into_iter().collect());
+    let mut writer = deflate.finish_into_writer().unwrap();
+    writer
+        .start_file("trailer.txt", SimpleFileOptions::default())
+        .unwrap();
+    writer.write_all(b"trailer").unwrap();
+    writer.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_from_zip_writer.zip").unwrap()).unwrap();
+    assert_eq!(archive.comment(), b"embedded");
+    assert_eq!(archive.len(), 10);
+    let mut content = String::new();
+    archive
+        .by_name("manual.txt")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "manual");
+    assert!(archive
+        .by_name("resources/normalfolder/level1/test1.ext1")
+        .is_ok());
+    assert!(archive.by_name("trailer.txt").is_ok());
+
+    remove_file("test_from_zip_writer.zip").unwrap();
+}
+
+#[test]
+fn verify_against_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_verify_normal.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+    let report = verify_against(
+        Path::new("test_verify_normal.zip"),
+        Path::new("resources/normalfolder"),
+        false,
+    )
+    .unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+
+    create_dir_all("test_verify").unwrap();
+    write("test_verify/a.txt", "123456").unwrap();
+    write("test_verify/b.txt", "123456").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_verify"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_verify.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+    write("test_verify/a.txt", "654321").unwrap();
+    remove_file("test_verify/b.txt").unwrap();
+    write("test_verify/c.txt", "123456").unwrap();
+
+    let report = verify_against(
+        Path::new("test_verify.zip"),
+        Path::new("test_verify"),
+        false,
+    )
+    .unwrap();
+    assert_eq!(report.mismatched, vec!["test_verify/a.txt"]);
+    assert_eq!(report.missing, vec!["test_verify/c.txt"]);
+    assert_eq!(report.extra, vec!["test_verify/b.txt"]);
+
+    // stored as test_verify_names/back/slash.txt
+    #[cfg(unix)]
+    {
+        create_dir_all("test_verify_names").unwrap();
+        write("test_verify_names/back\\slash.txt", "123456").unwrap();
+        let mut filter = SymlinkFilter::new(Path::new("test_verify_names"));
+        filter.scan();
+        let mut deflate = ZipDeflate::new(Path::new("test_verify_names.zip"));
+        deflate.write_archive(&filter.into_iter().collect());
+        deflate.finish().unwrap();
+        let report = verify_against(
+            Path::new("test_verify_names.zip"),
+            Path::new("test_verify_names"),
+            false,
+        )
+        .unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        remove_file("test_verify_names.zip").unwrap();
+        remove_dir_all("test_verify_names").unwrap();
+    }
+
+    assert!(matches!(
+        verify_against(
+            Path::new("test_verify_missing.zip"),
+            Path::new("test_verify"),
+            false
+        ),
+        Err(ClannadError::Io(_))
+    ));
+    write("test_verify_corrupt.zip", "not a zip archive").unwrap();
+    assert!(matches!(
+        verify_against(
+            Path::new("test_verify_corrupt.zip"),
+            Path::new("test_verify"),
+            false
+        ),
+        Err(ClannadError::Zip(_))
+    ));
+
+    remove_file("test_verify_corrupt.zip").unwrap();
+    remove_file("test_verify_normal.zip").unwrap();
+    remove_file("test_verify.zip").unwrap();
+    remove_dir_all("test_verify").unwrap();
+}
+
+#[test]
+fn checksum_sidecar_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_checksum_sidecar.zip"))
+        .with_checksum_sidecar(Path::new("test_checksum_sidecar.crc32"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let sidecar = std::fs::read_to_string("test_checksum_sidecar.crc32").unwrap();
+    let lines: Vec<&str> = sidecar.lines().collect();
+    let mut expected: Vec<String> = [
+        "resources/normalfolder/level1/test1.ext1",
+        "resources/normalfolder/level1/test1.ext2",
+        "resources/normalfolder/test1",
+    ]
+    .iter()
+    .map(|name| {
+        let content = std::fs::read(name).unwrap();
+        format!("{:08x}  {}", crc32fast::hash(&content), name)
+    })
+    .collect();
+    expected.sort();
+    let mut sorted = lines.clone();
+    sorted.sort();
+    assert_eq!(sorted, expected);
+
+    let mut archive = ZipArchive::new(File::open("test_checksum_sidecar.zip").unwrap()).unwrap();
+    for line in lines {
+        let (crc, name) = line.split_once("  ").unwrap();
+        let crc32 = archive.by_name(name).unwrap().crc32();
+        assert_eq!(crc, format!("{:08x}", crc32));
+    }
+
+    remove_file("test_checksum_sidecar.zip").unwrap();
+    remove_file("test_checksum_sidecar.crc32").unwrap();
+}
+
+#[test]
+fn error_variant_deflate() {
+    let error = ZipDeflateBuilder::new()
+        .build(Path::new("test_missing_root/archive.zip"))
+        .err()
+        .unwrap();
+    match error {
+        ClannadError::Io(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        other => panic!("unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn plan_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+
+    let stored = plan(&files, CompressionMethod::Stored);
+    assert_eq!((stored.files, stored.dirs, stored.symlinks), (3, 2, 3));
+    assert_eq!(stored.total_size, 6);
+    assert_eq!(stored.estimated_size, 6);
+
+    let deflated = plan(&files, CompressionMethod::Deflated);
+    assert_eq!(
+        (deflated.files, deflated.dirs, deflated.symlinks),
+        (3, 2, 3)
+    );
+    assert_eq!(deflated.estimated_size, 3);
+}
+
+#[test]
+fn extended_timestamps_deflate() {
+    let modified = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_700);
+    write("test_extended_timestamps.txt", "123456").unwrap();
+    OpenOptions::new()
+        .write(true)
+        .open("test_extended_timestamps.txt")
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let files = vec![FileInfo::new(
+        Path::new("test_extended_timestamps.txt"),
+        Path::new("test_extended_timestamps.txt"),
+        FileType::REGULAR,
+        None,
+    )];
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_extended_timestamps.zip")).with_extended_timestamps(true);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    // NTFS header id 0x000a, size 32, reserved, attribute tag 1 of size 24
+    let header = [
+        0x0a, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x18, 0x00,
+    ];
+    let archive = std::fs::read("test_extended_timestamps.zip").unwrap();
+    let start = archive
+        .windows(header.len())
+        .position(|w| w == header)
+        .expect("NTFS extra field is missing")
+        + header.len();
+    let mtime = u64::from_le_bytes(archive[start..start + 8].try_into().unwrap());
+    assert_eq!(
+        mtime,
+        116_444_736_000_000_000 + 1_600_000_000 * 10_000_000 + 1_234_567
+    );
+    let mut content = String::new();
+    ZipArchive::new(Cursor::new(archive))
+        .unwrap()
+        .by_name("test_extended_timestamps.txt")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "123456");
+
+    remove_file("test_extended_timestamps.txt").unwrap();
+    remove_file("test_extended_timestamps.zip").unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn owner_deflate() {
+    use std::os::unix::fs::MetadataExt;
+
+    write("test_owner.txt", "123456").unwrap();
+    let metadata = std::fs::metadata("test_owner.txt").unwrap();
+    let info = FileInfo::new(
+        Path::new("test_owner.txt"),
+        Path::new("test_owner.txt"),
+        FileType::REGULAR,
+        None,
+    );
+    assert_eq!(
+        (info.uid, info.gid),
+        (Some(metadata.uid()), Some(metadata.gid()))
+    );
+    let mut deflate = ZipDeflate::new(Path::new("test_owner.zip")).with_owner(true);
+    deflate.write_archive(&vec![info]);
+    deflate.finish().unwrap();
+
+    let mut expected = vec![0x75, 0x78, 11, 0, 1, 4];
+    expected.extend(metadata.uid().to_le_bytes());
+    expected.push(4);
+    expected.extend(metadata.gid().to_le_bytes());
+    let mut archive = ZipArchive::new(File::open("test_owner.zip").unwrap()).unwrap();
+    let entry = archive.by_name("test_owner.txt").unwrap();
+    let extra = entry.extra_data().expect("extra fields are missing");
+    assert!(extra.windows(expected.len()).any(|w| w == expected));
+
+    let mut deflate = ZipDeflate::new(Path::new("test_owner.zip"));
+    deflate.write_archive(&vec![FileInfo::new(
+        Path::new("test_owner.txt"),
+        Path::new("test_owner.txt"),
+        FileType::REGULAR,
+        None,
+    )]);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_owner.zip").unwrap()).unwrap();
+    let entry = archive.by_name("test_owner.txt").unwrap();
+    assert!(!entry
+        .extra_data()
+        .is_some_and(|extra| extra.windows(2).any(|w| w == [0x75, 0x78])));
+
+    remove_file("test_owner.txt").unwrap();
+    remove_file("test_owner.zip").unwrap();
+}
+
+#[test]
+fn made_by_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_made_by.zip")).with_made_by(0, 20);
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let bytes = std::fs::read("test_made_by.zip").unwrap();
+    let hosts: Vec<u8> = bytes
+        .windows(6)
+        .filter(|w| &w[..4] == b"PK\x01\x02")
+        .map(|w| w[5])
+        .collect();
+    assert_eq!(hosts, vec![0; 8]);
+    let mut archive = ZipArchive::new(File::open("test_made_by.zip").unwrap()).unwrap();
+    let file = archive.by_name("resources/normalfolder/test1").unwrap();
+    assert_eq!(file.version_made_by(), (2, 0));
+    assert_eq!(file.unix_mode(), None);
+    drop(file);
+    let dir = archive.by_name("resources/normalfolder/level1/").unwrap();
+    assert_eq!(dir.unix_mode(), Some(0o40775));
+    drop(dir);
+    remove_file("test_made_by.zip").unwrap();
+}
+
+#[test]
+fn messy_path_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources//normalfolder/./level1/"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+    assert!(files
+        .iter()
+        .all(|f| f.path.starts_with("resources/normalfolder/level1")));
+    let mut deflate = ZipDeflate::new(Path::new("test_messy_path.zip"));
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_messy_path.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "resources/normalfolder/level1/",
+            "resources/normalfolder/level1/test1.ext1",
+            "resources/normalfolder/level1/test1.ext2",
+        ]
+    );
+    remove_file("test_messy_path.zip").unwrap();
+}
+
+#[test]
+fn empty_dir_deflate() {
+    create_dir_all("test_empty_dir/inner/empty").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_empty_dir"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_empty_dir.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_empty_dir.zip").unwrap()).unwrap();
+    assert!(archive
+        .by_name("test_empty_dir/inner/empty/")
+        .unwrap()
+        .is_dir());
+    remove_file("test_empty_dir.zip").unwrap();
+    remove_dir_all("test_empty_dir").unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn verify_symlink_target_deflate() {
+    create_dir_all("test_verify_link").unwrap();
+    write("test_verify_link/a.txt", "123456").unwrap();
+    write("test_verify_link/b.txt", "123456").unwrap();
+    std::os::unix::fs::symlink("a.txt", "test_verify_link/link").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_verify_link"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_verify_link.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+    let report = verify_against(
+        Path::new("test_verify_link.zip"),
+        Path::new("test_verify_link"),
+        false,
+    )
+    .unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+
+    // same content behind the link, but a different target path
+    remove_file("test_verify_link/link").unwrap();
+    std::os::unix::fs::symlink("b.txt", "test_verify_link/link").unwrap();
+    let report = verify_against(
+        Path::new("test_verify_link.zip"),
+        Path::new("test_verify_link"),
+        false,
+    )
+    .unwrap();
+    assert_eq!(report.mismatched, vec!["test_verify_link/link"]);
+
+    remove_file("test_verify_link.zip").unwrap();
+    remove_dir_all("test_verify_link").unwrap();
+}
+
+#[test]
+fn shared_limiter_deflate() {
+    let limiter = Arc::new(Limiter::new(2));
+    let jobs: Vec<_> = (0..4)
+        .map(|i| {
+            let limiter = Arc::clone(&limiter);
+            thread::spawn(move || {
+                let name = format!("test_shared_limiter{}.zip", i);
+                let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+                filter.limit_open_files(Arc::clone(&limiter));
+                filter.scan();
+                let mut deflate = ZipDeflate::new(Path::new(&name)).with_limiter(limiter);
+                deflate.write_archive(&filter.into_iter().collect());
+                deflate.finish().unwrap();
+                name
+            })
+        })
+        .collect();
+    for job in jobs {
+        let name = job.join().unwrap();
+        let report = verify_against(Path::new(&name), Path::new("resources/normalfolder"), false);
+        assert!(report.is_ok(), "{:?}", report);
+        remove_file(&name).unwrap();
+    }
+    assert_eq!(limiter.available(), 2);
+}
+
+#[test]
+fn cancel_flag_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut deflate =
+        ZipDeflate::new(Path::new("test_cancel_flag.zip")).with_cancel_flag(Arc::clone(&cancel));
+    // raised while the second entry is handed over, after the first was written
+    deflate.write_archive_iter(filter.into_iter().enumerate().map(|(i, f)| {
+        if i == 1 {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        f
+    }));
+    assert!(matches!(deflate.finish(), Err(ClannadError::Cancelled)));
+
+    let archive = ZipArchive::new(File::open("test_cancel_flag.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 1);
+    assert!(archive.comment().starts_with(b"incomplete"));
+    remove_file("test_cancel_flag.zip").unwrap();
+}
+
+#[test]
+fn content_transform_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_content_transform.zip"))
+        .with_content_transform(Box::new(|_, content| {
+            content.iter().rev().copied().collect()
+        }));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_content_transform.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 8);
+    let mut content = String::new();
+    archive
+        .by_name("resources/normalfolder/level1/test1.ext1")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "654321");
+    assert!(archive
+        .by_name("resources/normalfolder/level1/")
+        .unwrap()
+        .is_dir());
+    assert!(archive
+        .by_name("resources/normalfolder/test")
+        .unwrap()
+        .is_symlink());
+
+    remove_file("test_content_transform.zip").unwrap();
+}
+
+#[test]
+fn sanitize_entry_name_rules() {
+    let accepted = [
+        ("a/b.txt", "a/b.txt"),
+        ("a//b/./c", "a/b/c"),
+        ("a\\b\\c.txt", "a/b/c.txt"),
+        ("dir/", "dir"),
+        ("./a", "a"),
+        ("...", "..."),
+        ("a..b/c", "a..b/c"),
+        ("console.txt", "console.txt"),
+        ("com10", "com10"),
+        ("x/a:b", "x/a:b"),
+        ("résumé.pdf", "résumé.pdf"),
+        ("dir/Lpt1", "dir/Lpt1"),
+        ("NUL.tar.gz", "NUL.tar.gz"),
+    ];
+    for (name, expected) in accepted {
+        for policy in [TraversalPolicy::Strip, TraversalPolicy::Reject] {
+            assert_eq!(
+                sanitize_entry_name(name, policy).unwrap(),
+                expected,
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    for (name, expected) in [
+        ("../evil", "evil"),
+        ("a/../../evil", "a/evil"),
+        ("a\\..\\evil", "a/evil"),
+    ] {
+        assert_eq!(
+            sanitize_entry_name(name, TraversalPolicy::Strip).unwrap(),
+            expected
+        );
+        assert!(sanitize_entry_name(name, TraversalPolicy::Reject)
+            .unwrap_err()
+            .to_string()
+            .contains("escapes the archive root"));
+    }
+
+    // left to the portability policy
+    for name in ["CON", "con.txt", "dir/Lpt1", "aux .log"] {
+        assert!(check_windows_name(name).is_err(), "{:?}", name);
+    }
+
+    let rejected = [
+        "",
+        ".",
+        "/",
+        "/etc/passwd",
+        "\\server\\share",
+        "..",
+        "C:/windows",
+        "c:evil",
+        "../C:/windows",
+        "a\nb",
+        "a\0b",
+        "tab\there",
+    ];
+    for name in rejected {
+        for policy in [TraversalPolicy::Strip, TraversalPolicy::Reject] {
+            assert!(
+                matches!(
+                    sanitize_entry_name(name, policy),
+                    Err(ClannadError::InvalidPath(_))
+                ),
+                "{:?}",
+                name
+            );
+        }
+    }
+}
+
+#[test]
+fn sanitize_entry_name_deflate() {
+    create_dir_all("test_reserved_name").unwrap();
+    write("test_reserved_name/aux.txt", "123456").unwrap();
+    write("test_reserved_name/kept.txt", "123456").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_reserved_name"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+    let mut deflate = ZipDeflate::new(Path::new("test_reserved_name.zip"));
+    deflate.write_archive(&files);
+    assert_eq!(deflate.warnings().len(), 1);
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_reserved_name.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "test_reserved_name/",
+            "test_reserved_name/aux.txt",
+            "test_reserved_name/kept.txt"
+        ]
+    );
+
+    let mut deflate = ZipDeflate::new(Path::new("test_reserved_name.zip"))
+        .with_portability(PortabilityPolicy::Reject);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_reserved_name.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["test_reserved_name/", "test_reserved_name/kept.txt"]
+    );
+
+    remove_file("test_reserved_name.zip").unwrap();
+    remove_dir_all("test_reserved_name").unwrap();
+}
+
+#[test]
+fn trailing_slash_root_deflate() {
+    let mut names = Vec::new();
+    for (root, archive_name) in [
+        ("resources/normalfolder", "test_no_trailing_slash.zip"),
+        ("resources/normalfolder/", "test_trailing_slash.zip"),
+    ] {
+        let mut filter = SymlinkFilter::new(Path::new(root));
+        filter.scan();
+        let mut deflate = ZipDeflate::new(Path::new(archive_name)).with_base(BaseMode::ExcludeRoot);
+        deflate.write_archive(&filter.into_iter().collect());
+        deflate.finish().unwrap();
+        let archive = ZipArchive::new(File::open(archive_name).unwrap()).unwrap();
+        let mut stored: Vec<String> = archive.file_names().map(|n| n.to_owned()).collect();
+        stored.sort();
+        names.push(stored);
+        remove_file(archive_name).unwrap();
+    }
+    assert_eq!(names[0].len(), 7);
+    assert_eq!(names[0], names[1]);
+
+    let mut plain = BasicFilter::new(Path::new("resources/normalfolder"));
+    plain.scan();
+    let mut slashed = BasicFilter::new(Path::new("resources/normalfolder/"));
+    slashed.scan();
+    assert_eq!(plain.files(), slashed.files());
+}
+
+#[test]
+fn flush_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+    let mut deflate = ZipDeflate::new(Path::new("test_flush.zip"));
+    deflate.write_archive(&files[..4].to_vec());
+    deflate.flush().unwrap();
+    deflate.write_archive(&files[4..].to_vec());
+    deflate.finish().unwrap();
+    let report = verify_against(
+        Path::new("test_flush.zip"),
+        Path::new("resources/normalfolder"),
+        false,
+    );
+    assert!(report.is_ok(), "{:?}", report);
+
+    let mut deflate = ZipDeflate::new(Path::new("test_flush_every.zip")).with_flush_every(2);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let report = verify_against(
+        Path::new("test_flush_every.zip"),
+        Path::new("resources/normalfolder"),
+        false,
+    );
+    assert!(report.is_ok(), "{:?}", report);
+
+    remove_file("test_flush.zip").unwrap();
+    remove_file("test_flush_every.zip").unwrap();
+}
+
+#[test]
+fn permission_policy_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_permission_policy.zip"))
+        .with_permission_policy(PermissionPolicy::Force {
+            file: 0o644,
+            dir: 0o755,
+        });
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_permission_policy.zip").unwrap()).unwrap();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).unwrap();
+        let mode = entry.unix_mode().unwrap() & 0o777;
+        if entry.is_dir() {
+            assert_eq!(mode, 0o755, "{}", entry.name());
+        } else if !entry.is_symlink() {
+            assert_eq!(mode, 0o644, "{}", entry.name());
+        }
+    }
+
+    remove_file("test_permission_policy.zip").unwrap();
+}
+
+#[test]
+fn archive_filter_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_archive_filter.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut filter = ArchiveFilter::new(Path::new("test_archive_filter.zip"));
+    filter.exclude(&["*.ext2".to_owned()]);
+    filter.scan();
+    let mut names: Vec<_> = filter
+        .files()
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|f| f.path.clone())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "resources/normalfolder",
+            "resources/normalfolder/current",
+            "resources/normalfolder/curtest",
+            "resources/normalfolder/level1",
+            "resources/normalfolder/level1/test1.ext1",
+            "resources/normalfolder/test",
+            "resources/normalfolder/test1",
+        ]
+    );
+    let link = filter
+        .files()
+        .as_ref()
+        .unwrap()
+        .iter()
+        .find(|f| f.path == "resources/normalfolder/test")
+        .unwrap();
+    assert_eq!(link.file_type, FileType::SYMLINK);
+    assert_eq!(link.symlink_path.as_deref(), Some("level1/test1.ext1"));
+
+    filter.restrict_to(&[Path::new("resources/normalfolder/level1")]);
+    assert_eq!(filter.len(), 2);
+
+    remove_file("test_archive_filter.zip").unwrap();
+}
+
+#[test]
+fn archive_filter_repack_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_archive_repack_src.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut filter = ArchiveFilter::new(Path::new("test_archive_repack_src.zip"));
+    filter.exclude(&["*.ext2".to_owned()]);
+    filter.scan();
+    let mut deflate = ZipDeflateBuilder::new()
+        .source_archive(Path::new("test_archive_repack_src.zip"))
+        .build(Path::new("test_archive_repack.zip"))
+        .unwrap();
+    deflate.write_archive(&filter.into_iter().collect());
+    assert!(deflate.skipped().is_empty());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_archive_repack.zip").unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().map(str::to_owned).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "resources/normalfolder/",
+            "resources/normalfolder/current",
+            "resources/normalfolder/curtest",
+            "resources/normalfolder/level1/",
+            "resources/normalfolder/level1/test1.ext1",
+            "resources/normalfolder/test",
+            "resources/normalfolder/test1",
+        ]
+    );
+    let mut content = Vec::new();
+    archive
+        .by_name("resources/normalfolder/level1/test1.ext1")
+        .unwrap()
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(
+        content,
+        std::fs::read("resources/normalfolder/level1/test1.ext1").unwrap()
+    );
+    assert!(archive
+        .by_name("resources/normalfolder/test")
+        .unwrap()
+        .is_symlink());
+
+    remove_file("test_archive_repack_src.zip").unwrap();
+    remove_file("test_archive_repack.zip").unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_content_path_deflate() {
+    create_dir_all("test_content_link").unwrap();
+    std::os::unix::fs::symlink("missing.txt", "test_content_link/dangling").unwrap();
+    let linked_file = FileInfo::new(
+        Path::new("copies/file"),
+        Path::new("resources/normalfolder/test"),
+        FileType::REGULAR,
+        None,
+    );
+    let linked_dir = FileInfo::new(
+        Path::new("copies/dir"),
+        Path::new("resources/normalfolder/current"),
+        FileType::DIRECTORY,
+        None,
+    );
+    let mut dangling = linked_file.clone();
+    dangling.path = "copies/dangling".to_owned();
+    dangling.content_path = "test_content_link/dangling".to_owned();
+    let mut deflate = ZipDeflate::new(Path::new("test_content_link.zip"));
+    deflate.write_archive(&vec![linked_file, linked_dir, dangling]);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_content_link.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 2);
+    let mut content = String::new();
+    archive
+        .by_name("copies/file")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(
+        content,
+        std::fs::read_to_string("resources/normalfolder/level1/test1.ext1").unwrap()
+    );
+    assert!(archive.by_name("copies/dir/").unwrap().is_dir());
+
+    remove_file("test_content_link.zip").unwrap();
+    remove_dir_all("test_content_link").unwrap();
+}
+
+#[test]
+fn archive_incremental_deflate() {
+    create_dir_all("test_incremental").unwrap();
+    write("test_incremental/a.txt", "a").unwrap();
+    write("test_incremental/b.txt", "b").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_incremental"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_incremental_full.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let archived = std::fs::metadata("test_incremental_full.zip")
+        .unwrap()
+        .modified()
+        .unwrap();
+    OpenOptions::new()
+        .write(true)
+        .open("test_incremental/b.txt")
+        .unwrap()
+        .set_modified(archived + std::time::Duration::from_secs(1))
+        .unwrap();
+    let written = archive_incremental(
+        Path::new("test_incremental"),
+        Path::new("test_incremental_full.zip"),
+        Path::new("test_incremental_delta.zip"),
+    )
+    .unwrap();
+    assert_eq!(written, 1);
+    let mut archive = ZipArchive::new(File::open("test_incremental_delta.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 1);
+    assert!(archive.by_name("test_incremental/b.txt").is_ok());
+
+    remove_file("test_incremental_full.zip").unwrap();
+    remove_file("test_incremental_delta.zip").unwrap();
+    remove_dir_all("test_incremental").unwrap();
+}
+
+#[test]
+fn rename_rule_deflate() {
+    create_dir_all("test_rename_rule/site").unwrap();
+    write("test_rename_rule/site/index.html.tmpl", "index").unwrap();
+    write("test_rename_rule/site/about.html.tmpl", "about").unwrap();
+    write("test_rename_rule/site/about.html", "stale").unwrap();
+    write("test_rename_rule/site/config.yml.in", "config").unwrap();
+    write("test_rename_rule/site/.tmpl", "kept").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_rename_rule/site"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_rename_rule.zip"))
+        .with_rename_rule(".tmpl", "")
+        .with_rename_rule(".yml.in", ".yml")
+        .with_rename_rule(".yml", ".yaml");
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_rename_rule.zip").unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "test_rename_rule/site/",
+            "test_rename_rule/site/.tmpl",
+            "test_rename_rule/site/about.html",
+            "test_rename_rule/site/config.yaml",
+            "test_rename_rule/site/index.html",
+        ]
+    );
+
+    remove_file("test_rename_rule.zip").unwrap();
+    remove_dir_all("test_rename_rule").unwrap();
+}
+
+#[test]
+fn link_policy_deflate() {
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+    let target = std::fs::read("resources/normalfolder/level1/test1.ext1").unwrap();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_link_policy_store.zip"));
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_link_policy_store.zip").unwrap()).unwrap();
+    assert!(archive
+        .by_name("resources/normalfolder/test")
+        .unwrap()
+        .is_symlink());
+    assert!(archive
+        .by_name("resources/normalfolder/current")
+        .unwrap()
+        .is_symlink());
+
+    let mut deflate = ZipDeflate::new(Path::new("test_link_policy_resolve.zip"))
+        .with_link_policy(LinkPolicy::Resolve);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let mut archive = ZipArchive::new(File::open("test_link_policy_resolve.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 8);
+    for name in [
+        "resources/normalfolder/test",
+        "resources/normalfolder/curtest",
+    ] {
+        let mut entry = archive.by_name(name).unwrap();
+        assert!(entry.is_file());
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        assert_eq!(content, target);
+    }
+    assert!(archive
+        .by_name("resources/normalfolder/current/")
+        .unwrap()
+        .is_dir());
+
+    remove_file("test_link_policy_store.zip").unwrap();
+    remove_file("test_link_policy_resolve.zip").unwrap();
+}
+
+#[test]
+fn skipped_deflate() {
+    create_dir_all("test_skipped").unwrap();
+    write("test_skipped/kept.txt", "kept").unwrap();
+    write("test_skipped/gone.txt", "gone").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_skipped"));
+    filter.scan();
+    remove_file("test_skipped/gone.txt").unwrap();
+    let mut deflate = ZipDeflate::new(Path::new("test_skipped.zip"));
+    deflate.write_archive(&filter.into_iter().collect());
+    assert_eq!(deflate.skipped().len(), 1);
+    assert_eq!(deflate.skipped()[0].0, "test_skipped/gone.txt");
+    assert!(matches!(deflate.skipped()[0].1, ClannadError::Skipped(_)));
+    deflate.finish().unwrap();
+
+    let archive = ZipArchive::new(File::open("test_skipped.zip").unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["test_skipped/", "test_skipped/kept.txt"]);
+
+    remove_file("test_skipped.zip").unwrap();
+    remove_dir_all("test_skipped").unwrap();
+}
+
+#[test]
+fn level_deflate() {
+    assert_eq!(level_range(CompressionMethod::Zstd), Some(1..=22));
+    assert_eq!(level_range(CompressionMethod::Stored), None);
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut deflate = ZipDeflate::new(Path::new("test_level.zip"))
+        .with_extension_methods(HashMap::from([(
+            "ext1".to_owned(),
+            CompressionMethod::Zstd,
+        )]))
+        .with_level(CompressionMethod::Zstd, 19)
+        .unwrap()
+        .with_level(CompressionMethod::Deflated, 6)
+        .unwrap();
+    deflate.write_archive(&filter.into_iter().collect());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_level.zip").unwrap()).unwrap();
+    assert_eq!(
+        archive
+            .by_name("resources/normalfolder/level1/test1.ext1")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Zstd
+    );
+    assert_eq!(
+        archive
+            .by_name("resources/normalfolder/level1/test1.ext2")
+            .unwrap()
+            .compression(),
+        CompressionMethod::Deflated
+    );
+
+    remove_file("test_level.zip").unwrap();
+}
+
+#[test]
+fn level_out_of_range_deflate() {
+    let err = ZipDeflateBuilder::default()
+        .level(CompressionMethod::Deflated, 19)
+        .err()
+        .unwrap();
+    assert!(matches!(err, ClannadError::InvalidOption(_)));
+    assert_eq!(
+        err.to_string(),
+        "Deflated does not accept compression level 19"
+    );
+    assert!(ZipDeflateBuilder::default()
+        .level(CompressionMethod::Stored, 1)
+        .is_err());
+    assert!(ZipDeflate::new(Path::new("test_level_range.zip"))
+        .with_level(CompressionMethod::Zstd, 23)
+        .is_err());
+    remove_file("test_level_range.zip").unwrap();
+}
+
+#[test]
+fn flatten_deflate() {
+    create_dir_all("test_flatten/a").unwrap();
+    create_dir_all("test_flatten/b/c").unwrap();
+    write("test_flatten/a/log.txt", "a").unwrap();
+    write("test_flatten/b/log.txt", "b").unwrap();
+    write("test_flatten/b/c/other.txt", "c").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_flatten"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_flatten.zip"))
+        .with_flatten(true)
+        .with_collision(CollisionPolicy::Number);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_flatten.zip").unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["log-1.txt", "log.txt", "other.txt"]);
+    assert!(!names.iter().any(|name| name.contains('/')));
+
+    let mut deflate = ZipDeflate::new(Path::new("test_flatten_fail.zip"))
+        .with_flatten(true)
+        .with_collision(CollisionPolicy::Fail)
+        .with_failure_policy(FailurePolicy::Abort);
+    deflate.write_archive(&files);
+    assert!(matches!(deflate.finish(), Err(ClannadError::Skipped(_))));
+    assert!(!Path::new("test_flatten_fail.zip").exists());
+
+    remove_file("test_flatten.zip").unwrap();
+    remove_dir_all("test_flatten").unwrap();
+}
+
+#[test]
+fn implied_dirs_deflate() {
+    let leaf = |path: &str| {
+        FileInfo::new(
+            Path::new(path),
+            Path::new("resources/normalfolder/test1"),
+            FileType::REGULAR,
+            None,
+        )
+    };
+    let mut deflate = ZipDeflate::new(Path::new("test_implied_dirs.zip")).with_implied_dirs(true);
+    deflate.write_archive(&vec![leaf("a/b/c.txt"), leaf("a/d.txt"), leaf("e.txt")]);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_implied_dirs.zip").unwrap()).unwrap();
+    let names: Vec<_> = archive.file_names().map(|n| n.to_owned()).collect();
+    assert_eq!(names, ["a/", "a/b/", "a/b/c.txt", "a/d.txt", "e.txt"]);
+    assert!(archive.by_name("a/b/").unwrap().is_dir());
+
+    remove_file("test_implied_dirs.zip").unwrap();
+}
+
+#[test]
+fn text_policy_deflate() {
+    create_dir_all("test_text_policy").unwrap();
+    write("test_text_policy/notes.txt", "first line\nsecond line\n").unwrap();
+    write(
+        "test_text_policy/data.bin",
+        [0x89, b'P', b'N', b'G', 0, 0, 0, 13],
+    )
+    .unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_text_policy"));
+    filter.scan();
+    let files: Vec<FileInfo> = filter.into_iter().collect();
+    // bit 0 of the internal attributes of each central directory header, by name
+    let text_flags = |archive: &str| {
+        let bytes = std::fs::read(archive).unwrap();
+        let mut flags: Vec<(String, u8)> = (0..bytes.len().saturating_sub(46))
+            .filter(|&at| &bytes[at..at + 4] == b"PK\x01\x02")
+            .map(|at| {
+                let name_len = usize::from(u16::from_le_bytes([bytes[at + 28], bytes[at + 29]]));
+                let name = String::from_utf8_lossy(&bytes[at + 46..at + 46 + name_len]);
+                (name.into_owned(), bytes[at + 36] & 1)
+            })
+            .collect();
+        flags.sort();
+        flags
+    };
+
+    for (policy, archive, text) in [
+        (TextPolicy::Detect, "test_text_detect.zip", [0, 1]),
+        (TextPolicy::Always, "test_text_always.zip", [1, 1]),
+        (TextPolicy::Never, "test_text_never.zip", [0, 0]),
+    ] {
+        let mut deflate = ZipDeflate::new(Path::new(archive)).with_text_policy(policy);
+        deflate.write_archive(&files);
+        deflate.finish().unwrap();
+        assert_eq!(
+            text_flags(archive),
+            [
+                ("test_text_policy/".to_owned(), 0),
+                ("test_text_policy/data.bin".to_owned(), text[0]),
+                ("test_text_policy/notes.txt".to_owned(), text[1]),
+            ]
+        );
+        let mut zip = ZipArchive::new(File::open(archive).unwrap()).unwrap();
+        let mut content = String::new();
+        zip.by_name("test_text_policy/notes.txt")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "first line\nsecond line\n");
+        remove_file(archive).unwrap();
+    }
+
+    remove_dir_all("test_text_policy").unwrap();
+}
+
+#[test]
+fn archive_log_deflate() {
+    create_dir_all("test_archive_log").unwrap();
+    write("test_archive_log/a.txt", "a").unwrap();
+    let (zip, index) = (
+        Path::new("test_archive_log.zip"),
+        Path::new("test_archive_log.index"),
+    );
+    let scan = || {
+        let mut filter = SymlinkFilter::new(Path::new("test_archive_log"));
+        filter.scan();
+        filter.into_iter().collect::<Vec<FileInfo>>()
+    };
+
+    let mut log = ArchiveLog::open(zip, index).unwrap();
+    assert_eq!(log.add_changes(&scan()), 2);
+    log.finish().unwrap();
+
+    write("test_archive_log/b.txt", "b").unwrap();
+    let mut log = ArchiveLog::open(zip, index).unwrap();
+    assert_eq!(log.len(), 2);
+    assert!(log.contains("test_archive_log/a.txt"));
+    assert_eq!(log.add_changes(&scan()), 1);
+    assert_eq!(log.add_changes(&scan()), 0);
+    log.finish().unwrap();
+
+    let mut indexed: Vec<String> = std::fs::read_to_string(index)
+        .unwrap()
+        .split_terminator('\0')
+        .map(|path| path.to_owned())
+        .collect();
+    indexed.sort();
+    assert_eq!(
+        indexed,
+        [
+            "test_archive_log",
+            "test_archive_log/a.txt",
+            "test_archive_log/b.txt"
+        ]
+    );
+    let archive = ZipArchive::new(File::open(zip).unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "test_archive_log/",
+            "test_archive_log/a.txt",
+            "test_archive_log/b.txt"
+        ]
+    );
+    assert_eq!(ArchiveLog::open(zip, index).unwrap().len(), 3);
+
+    remove_file(zip).unwrap();
+    remove_file(index).unwrap();
+    remove_dir_all("test_archive_log").unwrap();
+}
+
+#[test]
+fn destination_inside_root_deflate() {
+    create_dir_all("test_destination_inside").unwrap();
+    write("test_destination_inside/a.txt", "a").unwrap();
+    let mut deflate = ZipDeflate::new(Path::new("test_destination_inside/backup.zip"));
+    let mut filter = SymlinkFilter::new(Path::new("test_destination_inside"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().unwrap());
+    deflate.finish().unwrap();
+
+    let archive =
+        ZipArchive::new(File::open("test_destination_inside/backup.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["test_destination_inside/", "test_destination_inside/a.txt"]
+    );
+    remove_dir_all("test_destination_inside").unwrap();
+}
+
+#[test]
+fn small_files_deflate() {
+    create_dir_all("test_small_files").unwrap();
+    for i in 0..50 {
+        let extension = ["txt", "png", "dat"][i % 3];
+        write(
+            format!("test_small_files/{}.{}", i, extension),
+            format!("{}", i).repeat(i),
+        )
+        .unwrap();
+    }
+    let mut deflate = ZipDeflate::new(Path::new("test_small_files.zip"))
+        .with_extension_methods(HashMap::from([(
+            "png".to_owned(),
+            CompressionMethod::Stored,
+        )]))
+        .with_buffer_size(4);
+    let mut filter = SymlinkFilter::new(Path::new("test_small_files"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().unwrap());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_small_files.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 51);
+    for i in 0..50 {
+        let extension = ["txt", "png", "dat"][i % 3];
+        let mut entry = archive
+            .by_name(&format!("test_small_files/{}.{}", i, extension))
+            .unwrap();
+        let method = match extension {
+            "png" => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        };
+        assert_eq!(entry.compression(), method);
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, format!("{}", i).repeat(i));
+    }
+    remove_file("test_small_files.zip").unwrap();
+    remove_dir_all("test_small_files").unwrap();
+}
+
+#[test]
+fn manifest_deflate() {
+    create_dir_all("test_manifest").unwrap();
+    write("test_manifest/a.txt", "first ".repeat(100)).unwrap();
+    write("test_manifest/b.txt", "second ".repeat(100)).unwrap();
+    write(
+        "test_manifest/list.tsv",
+        "# source, stored name, method\n\
+         test_manifest/a.txt\tdocs/first.txt\tstored\n\
+         \n\
+         test_manifest/b.txt\tsecond.md\tdeflated\n\
+         test_manifest/a.txt\tcopy.txt\n",
+    )
+    .unwrap();
+    let manifest = Manifest::load(Path::new("test_manifest/list.tsv")).unwrap();
+    assert_eq!(manifest.files.len(), 3);
+    let mut deflate = ZipDeflate::new(Path::new("test_manifest.zip"))
+        .with_method(CompressionMethod::Bzip2)
+        .with_entry_methods(manifest.methods);
+    deflate.write_archive(&manifest.files);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_manifest.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["copy.txt", "docs/first.txt", "second.md"]);
+    for (name, method, content) in [
+        ("docs/first.txt", CompressionMethod::Stored, "first "),
+        ("second.md", CompressionMethod::Deflated, "second "),
+        ("copy.txt", CompressionMethod::Bzip2, "first "),
+    ] {
+        let mut entry = archive.by_name(name).unwrap();
+        assert_eq!(entry.compression(), method);
+        let mut stored = String::new();
+        entry.read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, content.repeat(100));
+    }
+
+    for invalid in ["only_source\n", "a\tb\tlzma\n", "\tname\n"] {
+        assert!(Manifest::parse(invalid).is_err());
+    }
+    remove_file("test_manifest.zip").unwrap();
+    remove_dir_all("test_manifest").unwrap();
+}
+
+#[test]
+fn cas_deflate() {
+    create_dir_all("test_cas/nested").unwrap();
+    write("test_cas/a.txt", "same content").unwrap();
+    write("test_cas/nested/b.txt", "same content").unwrap();
+    let mut deflate = CasDeflate::new(Path::new("test_cas.zip"));
+    let mut filter = SymlinkFilter::new(Path::new("test_cas"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().unwrap());
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_cas.zip").unwrap()).unwrap();
+    let blobs: Vec<String> = archive
+        .file_names()
+        .filter(|name| *name != CAS_MANIFEST)
+        .map(|name| name.to_owned())
+        .collect();
+    assert_eq!(blobs.len(), 1);
+    let mut manifest = String::new();
+    archive
+        .by_name(CAS_MANIFEST)
+        .unwrap()
+        .read_to_string(&mut manifest)
+        .unwrap();
+    let files: HashMap<&str, &str> = manifest
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, hash)| !hash.is_empty())
+        .collect();
+    assert_eq!(files.len(), 2);
+    let hash = files["test_cas/a.txt"];
+    assert_eq!(files["test_cas/nested/b.txt"], hash);
+    assert_eq!(blobs[0], format!("{}/{}/{}", &hash[..2], &hash[2..4], hash));
+    let mut content = String::new();
+    archive
+        .by_name(&blobs[0])
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "same content");
+
+    remove_file("test_cas.zip").unwrap();
+    remove_dir_all("test_cas").unwrap();
+}
+
+#[test]
+fn input_order_deflate() {
+    create_dir_all("test_input_order/sub").unwrap();
+    for name in ["b.txt", "a.txt", "sub/c.txt"] {
+        write(format!("test_input_order/{}", name), name).unwrap();
+    }
+    let mut filter = SymlinkFilter::new(Path::new("test_input_order"));
+    filter.scan();
+    let mut files = filter.files().clone().unwrap();
+    files.reverse();
+    files.swap(0, 1);
+    let mut deflate = ZipDeflate::new(Path::new("test_input_order.zip"));
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_input_order.zip").unwrap()).unwrap();
+    let names: Vec<String> = (0..archive.len())
+        .map(|i| archive.by_index(i).unwrap().name().to_owned())
+        .collect();
+    let expected: Vec<String> = files
+        .iter()
+        .map(|f| match f.file_type {
+            FileType::DIRECTORY => format!("{}/", f.path),
+            _ => f.path.clone(),
+        })
+        .collect();
+    assert_eq!(names, expected);
+    remove_file("test_input_order.zip").unwrap();
+    remove_dir_all("test_input_order").unwrap();
+}
+
+/// Objects of an `index_json` array as `(key, value)` pairs, string values unquoted.
+fn parse_index(json: &str) -> Vec<Vec<(String, String)>> {
+    let mut objects = Vec::new();
+    let mut chars = json.chars().peekable();
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut s = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next().unwrap() {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        s.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+        s
+    };
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            continue;
+        }
+        let mut object = Vec::new();
+        while chars.next() == Some('"') {
+            let key = string(&mut chars);
+            chars.next();
+            let value = match chars.peek() {
+                Some('"') => {
+                    chars.next();
+                    string(&mut chars)
+                }
+                _ => {
+                    let mut number = String::new();
+                    while chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        number.push(chars.next().unwrap());
+                    }
+                    number
+                }
+            };
+            object.push((key, value));
+            if chars.next() != Some(',') {
+                break;
+            }
+        }
+        objects.push(object);
+    }
+    objects
+}
+
+#[test]
+fn index_entry_deflate() {
+    create_dir_all("test_index_entry").unwrap();
+    write("test_index_entry/quote\"tab\t.txt", "1234").unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    let mut files = filter.files().clone().unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("test_index_entry"));
+    filter.scan();
+    files.extend(filter.files().clone().unwrap());
+    let mut deflate = ZipDeflate::new(Path::new("test_index_entry.zip")).with_index_entry(true);
+    deflate.write_archive(&files);
+    deflate.finish().unwrap();
+
+    let mut archive = ZipArchive::new(File::open("test_index_entry.zip").unwrap()).unwrap();
+    // the index lists every given entry, the one with a tab is not stored itself
+    assert_eq!(archive.len(), files.len());
+    let mut index = archive.by_index(0).unwrap();
+    assert_eq!(index.name(), INDEX_ENTRY_NAME);
+    let mut json = String::new();
+    index.read_to_string(&mut json).unwrap();
+    let objects = parse_index(&json);
+    assert_eq!(objects.len(), files.len());
+    for (object, f) in objects.iter().zip(&files) {
+        let mut expected = vec![
+            ("path".to_owned(), f.path.clone()),
+            ("size".to_owned(), f.size.to_string()),
+            ("type".to_owned(), format!("{:?}", f.file_type)),
+        ];
+        if let Some(target) = &f.symlink_path {
+            expected.push(("target".to_owned(), target.clone()));
+        }
+        assert_eq!(*object, expected);
+    }
+    drop(index);
+    remove_file("test_index_entry.zip").unwrap();
+    remove_dir_all("test_index_entry").unwrap();
+}
+
+#[test]
+fn portability_deflate() {
+    create_dir_all("test_portability").unwrap();
+    for name in ["aux.txt", "bad:name", "dots.", "ok.txt"] {
+        write(format!("test_portability/{}", name), name).unwrap();
+    }
+    let mut filter = SymlinkFilter::new(Path::new("test_portability"));
+    filter.scan();
+    let files = filter.files().clone().unwrap();
+
+    let mut deflate = ZipDeflate::new(Path::new("test_portability.zip"));
+    deflate.write_archive(&files);
+    let warned = |deflate: &ZipDeflate, name: &str| {
+        deflate
+            .warnings()
+            .iter()
+            .any(|w| w.starts_with(&format!("test_portability/{} ", name)))
+    };
+    assert_eq!(deflate.warnings().len(), 3);
+    for name in ["aux.txt", "bad:name", "dots."] {
+        assert!(warned(&deflate, name), "{}", name);
+    }
+    assert!(deflate.skipped().is_empty());
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_portability.zip").unwrap()).unwrap();
+    let mut names: Vec<&str> = archive.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "test_portability/",
+            "test_portability/aux.txt",
+            "test_portability/bad:name",
+            "test_portability/dots.",
+            "test_portability/ok.txt"
+        ]
+    );
+
+    let mut deflate = ZipDeflate::new(Path::new("test_portability.zip"))
+        .with_portability(PortabilityPolicy::Reject);
+    deflate.write_archive(&files);
+    assert!(deflate.warnings().is_empty());
+    let mut skipped: Vec<&str> = deflate.skipped().iter().map(|(p, _)| p.as_str()).collect();
+    skipped.sort();
+    assert_eq!(
+        skipped,
+        [
+            "test_portability/aux.txt",
+            "test_portability/bad:name",
+            "test_portability/dots."
+        ]
+    );
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_portability.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 2);
+
+    let mut deflate = ZipDeflate::new(Path::new("test_portability.zip"))
+        .with_portability(PortabilityPolicy::Ignore);
+    deflate.write_archive(&files);
+    assert!(deflate.warnings().is_empty() && deflate.skipped().is_empty());
+    deflate.finish().unwrap();
+    let archive = ZipArchive::new(File::open("test_portability.zip").unwrap()).unwrap();
+    assert_eq!(archive.len(), 5);
+
+    remove_file("test_portability.zip").unwrap();
+    remove_dir_all("test_portability").unwrap();
+}
+
+#[test]
+fn metadata_deflate() {
+    let metadata = HashMap::from([
+        ("build-id".to_owned(), "1234".to_owned()),
+        ("git-sha".to_owned(), "3f1d353".to_owned()),
+        ("hostname".to_owned(), "builder\tone\\two\nthree".to_owned()),
+    ]);
+    let mut deflate = ZipDeflate::new(Path::new("test_metadata.zip"));
+    deflate.set_metadata(metadata.clone()).unwrap();
+    let mut filter = SymlinkFilter::new(Path::new("resources/normalfolder"));
+    filter.scan();
+    deflate.write_archive(filter.files().as_ref().unwrap());
+    deflate.finish().unwrap();
+    assert_eq!(
+        read_metadata(Path::new("test_metadata.zip")).unwrap(),
+        metadata
+    );
+
+    let mut deflate = ZipDeflate::new(Path::new("test_metadata.zip"));
+    let too_long = HashMap::from([("key".to_owned(), "x".repeat(1 << 16))]);
+    assert!(matches!(
+        deflate.set_metadata(too_long),
+        Err(ClannadError::InvalidMetadata(_))
+    ));
+    deflate.finish().unwrap();
+    assert!(read_metadata(Path::new("test_metadata.zip"))
+        .unwrap()
+        .is_empty());
+
+    let mut writer = ZipWriter::new(File::create("test_metadata.zip").unwrap());
+    writer.set_comment("clannad-metadata\nno separator");
+    writer.finish().unwrap();
+    assert!(matches!(
+        read_metadata(Path::new("test_metadata.zip")),
+        Err(ClannadError::InvalidMetadata(_))
+    ));
+    remove_file("test_metadata.zip").unwrap();
+}